@@ -0,0 +1,236 @@
+use std::os::fd::AsRawFd;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use hifitime::Epoch;
+use tokio::sync::Mutex;
+
+use crate::config::size::BlockSize;
+use crate::config::{Args, Status, DEFAULT_BLOCK_SIZE};
+use crate::environment::progress::human_rate;
+use crate::environment::statistics::DdContext;
+use crate::io::device;
+use crate::io::error::{DdError, IoError};
+use crate::io::sink::config::SinkConfig;
+use crate::io::sink::core::DataSink;
+use crate::io::source::config::SourceConfig;
+use crate::io::source::core::DataSource;
+
+pub const BENCH_BLOCK_SIZES: [usize; 6] = [4 << 10, 16 << 10, 64 << 10, 256 << 10, 1 << 20, 4 << 20];
+pub const BENCH_QUEUE_DEPTHS: [usize; 3] = [1, 8, 32];
+/// Bytes copied per trial, a multiple of every block size tried.
+pub const BENCH_TRIAL_BYTES: u64 = 32 << 20;
+/// The bs=auto probe only tries sizes worth a whole copy, and reads less.
+const PROBE_BLOCK_SIZES: [usize; 4] = [64 << 10, 256 << 10, 1 << 20, 4 << 20];
+const PROBE_TRIAL_BYTES: u64 = 8 << 20;
+/// Trials this close to the fastest count as just as fast, the smaller setting is recommended.
+const CLOSE_ENOUGH: f64 = 0.95;
+
+/// Throughput of one timed copy, in bytes per second.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trial {
+  pub block_size: usize,
+  pub queue_depth: usize,
+  pub bytes: u64,
+  pub read_rate: f64,
+  pub write_rate: f64,
+  // from the first read until everything was written and synced
+  pub rate: f64,
+}
+
+/// Size and offset of each trial of a run over `input_size` bytes: consecutive pieces of the
+/// input while there are any left, so trials do not read what an earlier one left in a cache.
+fn trial_ranges(input_size: u64, trial_bytes: u64, trials: usize) -> Vec<(u64, u64)> {
+  let length = trial_bytes.min(input_size);
+  let pieces = (input_size / trial_bytes).max(1);
+  (0..trials as u64).map(|trial| ((trial % pieces) * length, length)).collect()
+}
+
+/// Size of the input, which has to be a file or block device that can be read at any offset.
+fn input_size(args: &Args) -> Result<u64, DdError> {
+  let path = args.input_file.clone().unwrap_or_default();
+  let file = std::fs::File::open(&path).map_err(|e| IoError::InputFileOpenError(format!("{}: {}", path, e)).caused_by(&e))?;
+  if !device::is_positional(file.as_raw_fd()) {
+    return Err(DdError::OtherError(format!("{} is not a file or block device, there is nothing to time", path)));
+  }
+  Ok(device::file_size(file.as_raw_fd()).map_err(|e| IoError::InputFileOpenError(e.to_string()).caused_by(&e))?)
+}
+
+/// Copies `length` bytes from `offset` of the input at the given block size and queue depth.
+/// Without an output the blocks go to /dev/null, which times the reads alone.
+async fn trial(args: &Args, block_size: usize, queue_depth: usize, (offset, length): (u64, u64), output: Option<PathBuf>) -> Result<Trial, DdError> {
+  let source_cfg = SourceConfig {
+    block_size,
+    buffer_size: block_size,
+    skip: offset as usize / block_size,
+    count: Some((length as usize).div_ceil(block_size)),
+    // what was read has to come from the device again in the next trials
+    nocache: true,
+    // tokio reads files in pieces of at most 2 MiB
+    fullblock: true,
+    ..SourceConfig::from(args)
+  };
+  let sink_cfg = match output {
+    Some(output) => SinkConfig {
+      output_file: Some(output),
+      block_size,
+      reblock: false,
+      verify: false,
+      // a trial is done once the data is on the device, and leaves no pages behind
+      fdatasync: true,
+      nocache: true,
+      ..SinkConfig::from(args)
+    },
+    None => SinkConfig { output_file: Some(PathBuf::from("/dev/null")), block_size, ..SinkConfig::default() },
+  };
+
+  let context = Arc::new(Mutex::new(DdContext::new()));
+  let notifications = context.lock().await.main_notifications.clone();
+  let (sender, receiver) = tokio::sync::mpsc::channel(queue_depth);
+  DataSource::run(sender, source_cfg, context.clone()).await?;
+  DataSink::run(receiver, sink_cfg, context.clone()).await?;
+  loop {
+    notifications.notified().await;
+    if !context.lock().await.are_tasks_pending().await {
+      break;
+    }
+  }
+  let finished = Epoch::now().unwrap();
+  let context = context.lock().await;
+  if let Some(failure) = context.failure.lock().await.take() {
+    return Err(failure.into());
+  }
+  let read = context.read_statistics.lock().await.clone();
+  let write = context.write_statistics.lock().await.clone();
+  let rate = |bytes: u64, seconds: f64| bytes as f64 / seconds;
+  Ok(Trial {
+    block_size,
+    queue_depth,
+    bytes: write.total_bytes_written,
+    read_rate: rate(read.total_bytes_read, (read.last_read_at - read.started_at).to_seconds()),
+    write_rate: rate(write.total_bytes_written, (write.last_write_at - write.started_at).to_seconds()),
+    rate: rate(write.total_bytes_written, (finished - read.started_at).to_seconds()),
+  })
+}
+
+/// The smallest block size and queue depth about as fast as the fastest trial, they need the
+/// least memory.
+pub fn recommend(trials: &[Trial]) -> Option<&Trial> {
+  let fastest = trials.iter().map(|trial| trial.rate).fold(0.0, f64::max);
+  trials
+    .iter()
+    .filter(|trial| trial.rate >= fastest * CLOSE_ENOUGH)
+    .min_by_key(|trial| (trial.block_size, trial.queue_depth))
+}
+
+/// Writes a byte count the way it would be given on the command line, `64K` or `1M`.
+fn size_operand(bytes: usize) -> String {
+  match bytes {
+    _ if bytes >= 1 << 20 && bytes.is_multiple_of(1 << 20) => format!("{}M", bytes >> 20),
+    _ if bytes >= 1 << 10 && bytes.is_multiple_of(1 << 10) => format!("{}K", bytes >> 10),
+    _ => bytes.to_string(),
+  }
+}
+
+pub fn table(trials: &[Trial]) -> String {
+  let mut out = format!("{:>10} {:>11} {:>12} {:>12} {:>12}\n", "bs", "queue-depth", "read", "write", "copy");
+  for trial in trials {
+    out += &format!(
+      "{:>10} {:>11} {:>12} {:>12} {:>12}\n",
+      size_operand(trial.block_size),
+      trial.queue_depth,
+      human_rate(trial.read_rate),
+      human_rate(trial.write_rate),
+      human_rate(trial.rate),
+    );
+  }
+  out
+}
+
+/// Bench mode, times copies of the input to the output for every block size and queue depth
+/// and prints them with the recommended setting.
+pub async fn run(args: &Args) -> Result<(), DdError> {
+  let size = input_size(args)?;
+  if size == 0 {
+    return Err(DdError::OtherError("the input is empty, there is nothing to time".to_string()));
+  }
+  let mut settings = Vec::new();
+  for block_size in BENCH_BLOCK_SIZES {
+    for depth in BENCH_QUEUE_DEPTHS {
+      // --buffer-mem caps the depth as it would in a copy
+      let depth = args.queue_depth_for(block_size, Some(depth));
+      if !settings.contains(&(block_size, depth)) {
+        settings.push((block_size, depth));
+      }
+    }
+  }
+  let ranges = trial_ranges(size, BENCH_TRIAL_BYTES, settings.len());
+  let output = args.output_file.clone().map(PathBuf::from);
+  eprintln!("Timing {} trials of {} bytes each", settings.len(), ranges[0].1);
+  let mut trials = Vec::new();
+  for ((block_size, depth), range) in settings.into_iter().zip(ranges) {
+    trials.push(trial(args, block_size, depth, range, output.clone()).await?);
+  }
+  print!("{}", table(&trials));
+  if let Some(best) = recommend(&trials) {
+    println!("recommended: --bs {} --queue-depth {} ({})", size_operand(best.block_size), best.queue_depth, human_rate(best.rate));
+  }
+  Ok(())
+}
+
+/// bs=auto, reads a little of the input at a few block sizes and returns `args` with the
+/// fastest one. Inputs that cannot be read at an offset keep the default block size.
+pub async fn tune(args: &Args) -> Result<Args, DdError> {
+  let block_size = match input_size(args) {
+    Ok(size) if size > 0 => {
+      let ranges = trial_ranges(size, PROBE_TRIAL_BYTES, PROBE_BLOCK_SIZES.len());
+      let mut trials = Vec::new();
+      for (block_size, range) in PROBE_BLOCK_SIZES.into_iter().zip(ranges) {
+        trials.push(trial(args, block_size, args.queue_depth_for(block_size, args.queue_depth), range, None).await?);
+      }
+      recommend(&trials).map_or(DEFAULT_BLOCK_SIZE, |best| best.block_size)
+    },
+    _ => {
+      eprintln!("ruplica: warning: bs=auto needs an input file or block device to probe, using {} byte blocks", DEFAULT_BLOCK_SIZE);
+      DEFAULT_BLOCK_SIZE
+    },
+  };
+  if args.status != Some(Status::None) {
+    eprintln!("bs=auto picked {}", size_operand(block_size));
+  }
+  Ok(Args { bs: Some(BlockSize::Bytes(block_size)), ..args.clone() })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn timed(block_size: usize, queue_depth: usize, rate: f64) -> Trial {
+    Trial { block_size, queue_depth, bytes: 0, read_rate: rate, write_rate: rate, rate }
+  }
+
+  #[test]
+  fn test_recommend_prefers_smaller_settings() {
+    let trials = [timed(4096, 1, 100.0), timed(65536, 8, 960.0), timed(65536, 32, 990.0), timed(1 << 20, 8, 1000.0)];
+    assert_eq!(recommend(&trials), Some(&trials[1]));
+    assert_eq!(recommend(&[]), None);
+  }
+
+  #[test]
+  fn test_trial_ranges() {
+    assert_eq!(trial_ranges(100, 40, 3), vec![(0, 40), (40, 40), (0, 40)]);
+    assert_eq!(trial_ranges(10, 40, 2), vec![(0, 10), (0, 10)]);
+  }
+
+  #[tokio::test]
+  async fn test_trial_copies_the_range() {
+    let dir = tempfile::tempdir().unwrap();
+    let (input, output) = (dir.path().join("input"), dir.path().join("output"));
+    let data: Vec<u8> = (0..1u32 << 16).map(|i| i as u8).collect();
+    std::fs::write(&input, &data).unwrap();
+    let args = <Args as clap::Parser>::parse_from(["ruplica", "--if", input.to_str().unwrap()]);
+    let timed = trial(&args, 4096, 2, (16384, 8192), Some(output.clone())).await.unwrap();
+    assert_eq!((timed.block_size, timed.queue_depth, timed.bytes), (4096, 2, 8192));
+    assert_eq!(std::fs::read(&output).unwrap(), &data[16384..24576]);
+  }
+}
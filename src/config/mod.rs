@@ -1,7 +1,156 @@
-use clap::Parser;
+use std::ffi::OsString;
 
-#[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+use clap::{CommandFactory, Parser, ValueEnum};
+
+use crate::io::digest::HashSelection;
+use crate::io::throttle::Limits;
+use crate::manifest::ManifestFormat;
+
+pub mod size;
+use size::{parse_block_size, parse_size, BlockSize};
+
+pub const DEFAULT_BLOCK_SIZE: usize = 512;
+/// Most blocks queued between reading and writing unless --queue-depth asks for more.
+pub const MAX_QUEUE_DEPTH: usize = 1024;
+
+/// dd style `conv=` conversions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Conv {
+    /// Do not truncate the output file
+    Notrunc,
+    /// Continue after read errors
+    Noerror,
+    /// Pad every input block to ibs with zeros
+    Sync,
+    /// Skip holes in the input and seek over all-zero output blocks instead of writing them
+    Sparse,
+    /// Physically write output file data and metadata before finishing
+    Fsync,
+    /// Physically write output file data before finishing
+    Fdatasync,
+    /// Fail if the output file already exists
+    Excl,
+    /// Do not create the output file
+    Nocreat,
+}
+
+/// dd style `iflag=` and `oflag=` flags, for named files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Flag {
+    /// Bypass the page cache, block sizes and offsets must be multiples of the logical block size
+    Direct,
+    /// Open with O_DSYNC, every write reaches the device before it returns
+    Dsync,
+    /// Open with O_SYNC, like dsync and the metadata too
+    Sync,
+    /// Drop the cached pages of the data once it was copied
+    Nocache,
+    /// Keep reading until a whole input block is filled or the input ends (iflag only)
+    Fullblock,
+}
+
+/// dd style `status=` levels, without one the summary is printed at exit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Status {
+    /// Print nothing but errors
+    None,
+    /// Leave out the final transfer line of the summary
+    Noxfer,
+    /// Keep a progress line updated while copying
+    Progress,
+}
+
+/// What to do when the input is modified while it is being copied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SourceChange {
+    /// Finish the copy and warn about it in the report
+    Warn,
+    /// Stop the copy with an error
+    Fail,
+    /// Start the copy over, a few times at most
+    Retry,
+}
+
+/// What moves the blocks of a copy between two files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Engine {
+    /// tokio tasks reading and writing one block at a time
+    Tokio,
+    /// Many reads and writes in flight through io_uring, for file to file copies without
+    /// hashing or conversions (needs the uring cargo feature, falls back to tokio otherwise)
+    Uring,
+}
+
+/// I/O scheduling classes for `--ioprio-class`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum IoClass {
+    /// Only get disk time nobody else wants
+    Idle,
+    /// The default class, shared out by level
+    BestEffort,
+    /// Served before everything else (needs CAP_SYS_ADMIN)
+    Realtime,
+}
+
+/// How a fatal error is reported on stderr.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ErrorFormat {
+    /// A human readable message
+    Text,
+    /// One JSON object with kind, exit_code, message, offset and errno
+    Json,
+}
+
+/// Rewrites dd style `key=value` operands into `--key=value` for every long option we know,
+/// so `ruplica if=/x of=/y bs=4M` works like `ruplica --if /x --of /y --bs 4M`.
+pub fn translate_operands<I: IntoIterator<Item = OsString>>(args: I) -> Vec<OsString> {
+    let command = Args::command();
+    let mut args = args.into_iter();
+    let mut out: Vec<OsString> = args.next().into_iter().collect();
+    for arg in args {
+        let translated = arg.to_str().and_then(|operand| {
+            let (key, _) = operand.split_once('=')?;
+            let known = !operand.starts_with('-') && command.get_arguments().any(|a| a.get_long() == Some(key));
+            known.then(|| OsString::from(format!("--{}", operand)))
+        });
+        out.push(translated.unwrap_or(arg));
+    }
+    out
+}
+
+/// The --error-format of a command line that may not parse, the last one given wins.
+pub fn requested_error_format(args: &[OsString]) -> ErrorFormat {
+    let mut format = ErrorFormat::Text;
+    let mut args = args.iter().filter_map(|arg| arg.to_str());
+    while let Some(arg) = args.next() {
+        let value = match arg.strip_prefix("--error-format") {
+            Some("") => args.next(),
+            Some(value) => value.strip_prefix('='),
+            None => None,
+        };
+        if let Some(value) = value.and_then(|value| ErrorFormat::from_str(value, true).ok()) {
+            format = value;
+        }
+    }
+    format
+}
+
+const EXIT_STATUS_HELP: &str = "Exit status:
+  0  success
+  1  other error
+  2  usage error
+  3  input could not be opened
+  4  output could not be opened
+  5  read error
+  6  write error
+  7  digest or verification mismatch
+  8  interrupted by SIGINT or SIGTERM
+  9  partial copy, conv=noerror skipped unreadable blocks
+ 10  input changed while it was being read";
+
+#[derive(Parser, Debug, Clone)]
+// like dd, an operand given twice takes the last value
+#[command(version, about, long_about = None, after_help = EXIT_STATUS_HELP, args_override_self = true)]
 pub struct Args {
     /// Input file (default: stdin)
     #[arg(long = "if")]
@@ -11,26 +160,375 @@ pub struct Args {
     #[arg(long = "of")]
     pub output_file: Option<String>,
 
-    /// Block size (in bytes, default: 512)
-    #[arg(long, default_value = "512")]
-    pub bs: usize,
+    /// Block size for both reading and writing, overrides ibs and obs (default: 512, suffixes like K, MB, GiB work).
+    /// `auto` times a few reads of the input first and takes the fastest size
+    #[arg(long, value_parser = parse_block_size)]
+    pub bs: Option<BlockSize>,
 
-    /// Number of blocks to copy
-    #[arg(long)]
+    /// Input block size (in bytes, default: 512)
+    #[arg(long, value_parser = parse_size)]
+    pub ibs: Option<usize>,
+
+    /// Output block size, writes are re-blocked to it unless bs is given (in bytes, default: 512)
+    #[arg(long, value_parser = parse_size)]
+    pub obs: Option<usize>,
+
+    /// Number of input blocks to copy
+    #[arg(long, value_parser = parse_size)]
     pub count: Option<usize>,
 
-    /// Skip blocks at start of input
-    #[arg(long, default_value = "0")]
+    /// Skip ibs-sized blocks at start of input
+    #[arg(long, default_value = "0", value_parser = parse_size)]
     pub skip: usize,
 
-    /// Seek blocks at start of output
-    #[arg(long, default_value = "0")]
+    /// Seek obs-sized blocks at start of output
+    #[arg(long, default_value = "0", value_parser = parse_size)]
     pub seek: usize,
+
+    /// Comma separated list of conversions
+    #[arg(long, value_enum, value_delimiter = ',')]
+    pub conv: Vec<Conv>,
+
+    /// Comma separated list of input flags
+    #[arg(long, value_enum, value_delimiter = ',')]
+    pub iflag: Vec<Flag>,
+
+    /// Comma separated list of output flags
+    #[arg(long, value_enum, value_delimiter = ',')]
+    pub oflag: Vec<Flag>,
+
+    /// Level of information printed to stderr: none, noxfer or progress
+    #[arg(long, value_enum)]
+    pub status: Option<Status>,
+
+    /// Compute BLAKE2b-512 digests of the data read and written
+    #[arg(long)]
+    pub blake2b: bool,
+
+    /// Compute SHA3-512 digests of the data read and written
+    #[arg(long)]
+    pub sha3: bool,
+
+    /// Compute CRC32 checksums of the data read and written
+    #[arg(long)]
+    pub crc32: bool,
+
+    /// Write the digests of the output file to this manifest
+    #[arg(long, requires = "output_file")]
+    pub manifest: Option<String>,
+
+    /// Re-read the output after writing and compare it with what was read from the input
+    #[arg(long, requires = "output_file")]
+    pub verify: bool,
+
+    /// Verify the input (or the files the manifest names) against a manifest instead of copying
+    #[arg(long, conflicts_with_all = ["output_file", "manifest", "verify"])]
+    pub check: Option<String>,
+
+    /// Manifest layout, text formats enable the hasher they need
+    #[arg(long, value_enum, default_value = "b2sum")]
+    pub manifest_format: ManifestFormat,
+
+    /// Instead of copying, time short copies of the input at several block sizes and queue depths
+    /// and print which is fastest. A given output is overwritten, without one only reads are timed
+    #[arg(long, requires = "input_file", conflicts_with_all = ["check", "manifest", "verify", "skip", "seek", "count"])]
+    pub bench: bool,
+
+    /// Keep a journal here while copying so an interrupted copy can be continued with --resume
+    #[arg(long, requires_all = ["input_file", "output_file"], conflicts_with_all = ["check", "rescue", "resume"])]
+    pub journal: Option<String>,
+
+    /// Continue the copy this journal was written for, from its last committed offset
+    #[arg(long, requires_all = ["input_file", "output_file"], conflicts_with_all = ["check", "rescue"])]
+    pub resume: Option<String>,
+
+    /// Rescue mode: keep going past read errors, retry bad areas and track them in this GNU ddrescue mapfile.
+    /// The first pass reads ibs or bs sized blocks, 64K when neither is given
+    #[arg(
+        long,
+        requires_all = ["input_file", "output_file"],
+        conflicts_with_all = ["check", "verify", "manifest", "skip", "seek", "count", "conv", "iflag", "oflag"],
+    )]
+    pub rescue: Option<String>,
+
+    /// Read size used to trim and retry bad areas in rescue mode
+    #[arg(long, default_value = "512", value_parser = parse_size)]
+    pub rescue_sector: usize,
+
+    /// Number of retry passes over bad sectors in rescue mode, alternating direction
+    #[arg(long, default_value = "1")]
+    pub rescue_retries: usize,
+
+    /// What to do when the input's inode, size, mtime or ctime change during the copy
+    #[arg(long, value_enum, default_value = "warn")]
+    pub on_source_change: SourceChange,
+
+    /// Blocks that can wait between reading and writing (default: as many as --buffer-mem holds, at most 1024)
+    #[arg(long)]
+    pub queue_depth: Option<usize>,
+
+    /// Memory the blocks waiting between reading and writing may take (suffixes like K, MB, GiB work)
+    #[arg(long, default_value = "64M", value_parser = parse_size)]
+    pub buffer_mem: usize,
+
+    /// Tasks reading disjoint blocks of the input at once, when it is a file or block device
+    #[arg(long, default_value = "1")]
+    pub readers: usize,
+
+    /// Tasks writing blocks at their offsets at once, when the output is a file or block device
+    #[arg(long, default_value = "1")]
+    pub writers: usize,
+
+    /// Most bytes read per second (suffixes like K, MB, GiB work)
+    #[arg(long, value_parser = parse_size)]
+    pub rate: Option<usize>,
+
+    /// Most reads per second
+    #[arg(long)]
+    pub iops: Option<usize>,
+
+    /// File with `rate=` and `iops=` lines read again on SIGHUP, to change the limits while copying
+    #[arg(long)]
+    pub control: Option<String>,
+
+    /// I/O scheduling class of the process
+    #[arg(long, value_enum)]
+    pub ioprio_class: Option<IoClass>,
+
+    /// Level within the I/O scheduling class, 0 is served first (default: 4)
+    #[arg(long, requires = "ioprio_class", value_parser = clap::value_parser!(u8).range(0..=7))]
+    pub ioprio_level: Option<u8>,
+
+    /// CPU scheduling niceness of the process, from -20 to 19
+    #[arg(long, allow_negative_numbers = true, value_parser = clap::value_parser!(i32).range(-20..=19))]
+    pub nice: Option<i32>,
+
+    /// I/O engine for file to file copies
+    #[arg(long, value_enum, default_value = "tokio")]
+    pub engine: Engine,
+
+    /// Always copy through ruplica's own buffers, even when the kernel could copy directly
+    #[arg(long)]
+    pub no_zero_copy: bool,
+
+    /// Format of the error report printed when the run fails
+    #[arg(long, value_enum, default_value = "text")]
+    pub error_format: ErrorFormat,
 }
 
 impl Args {
-    pub fn create() -> Self {
-        Args::parse()
+    /// Parses and checks the command line after `translate_operands`, what is wrong with it
+    /// comes back as a clap error for `main` to report in the requested error format.
+    pub fn create(args: Vec<OsString>) -> Result<Self, clap::Error> {
+        let args = Args::try_parse_from(args)?;
+        if args.has_conv(Conv::Excl) && args.has_conv(Conv::Nocreat) {
+            return Err(Args::command()
+                .error(clap::error::ErrorKind::ArgumentConflict, "cannot combine conv=excl and conv=nocreat"));
+        }
+        let journaled = args.journal.is_some() || args.resume.is_some();
+        if journaled && (args.has_conv(Conv::Sync) || args.has_conv(Conv::Noerror)) {
+            // padded or skipped input blocks break the mapping of output offsets to input offsets
+            return Err(Args::command()
+                .error(clap::error::ErrorKind::ArgumentConflict, "cannot journal a copy with conv=sync or conv=noerror"));
+        }
+        if (args.has_iflag(Flag::Direct) && args.input_file.is_none()) || (args.has_oflag(Flag::Direct) && args.output_file.is_none()) {
+            return Err(Args::command()
+                .error(clap::error::ErrorKind::ArgumentConflict, "direct I/O needs a named file, not stdin or stdout"));
+        }
+        if args.has_oflag(Flag::Fullblock) {
+            return Err(Args::command()
+                .error(clap::error::ErrorKind::InvalidValue, "fullblock is an input flag, use iflag=fullblock"));
+        }
+        let counted = args.skip > 0 || args.seek > 0 || args.count.is_some();
+        if args.bs == Some(BlockSize::Auto) && (counted || args.bench || args.check.is_some() || args.rescue.is_some() || args.resume.is_some()) {
+            // skip, seek and count are in blocks, and a resumed copy has to keep its block size
+            return Err(Args::command()
+                .error(clap::error::ErrorKind::ArgumentConflict, "bs=auto only works for plain copies, without skip, seek, count, --resume, --rescue, --check or --bench"));
+        }
+        if args.queue_depth == Some(0) || args.buffer_mem == 0 {
+            return Err(Args::command()
+                .error(clap::error::ErrorKind::InvalidValue, "--queue-depth and --buffer-mem must be greater than zero"));
+        }
+        if args.rate == Some(0) || args.iops == Some(0) {
+            return Err(Args::command()
+                .error(clap::error::ErrorKind::InvalidValue, "--rate and --iops must be greater than zero, leave them out for no limit"));
+        }
+        if args.readers == 0 || args.writers == 0 {
+            return Err(Args::command()
+                .error(clap::error::ErrorKind::InvalidValue, "--readers and --writers need at least one task"));
+        }
+        if args.ibs() == 0 || args.obs() == 0 || args.rescue_sector == 0 {
+            return Err(Args::command()
+                .error(clap::error::ErrorKind::InvalidValue, "block sizes must be greater than zero"));
+        }
+        Ok(args)
+    }
+
+    /// The bs given in bytes, none for bs=auto until the probe has replaced it.
+    pub fn block_size(&self) -> Option<usize> {
+        match self.bs {
+            Some(BlockSize::Bytes(bs)) => Some(bs),
+            _ => None,
+        }
+    }
+
+    pub fn ibs(&self) -> usize {
+        self.block_size().or(self.ibs).unwrap_or(DEFAULT_BLOCK_SIZE)
+    }
+
+    pub fn obs(&self) -> usize {
+        self.block_size().or(self.obs).unwrap_or(DEFAULT_BLOCK_SIZE)
+    }
+
+    /// Blocks the queue from the source to the sink holds: --queue-depth, as far as --buffer-mem
+    /// allows, but always at least one.
+    pub fn queue_depth(&self) -> usize {
+        self.queue_depth_for(self.ibs().max(self.obs()), self.queue_depth)
+    }
+
+    /// `queue_depth` for blocks of `block_size` bytes, `depth` standing in for --queue-depth.
+    pub fn queue_depth_for(&self, block_size: usize, depth: Option<usize>) -> usize {
+        let budget = (self.buffer_mem / block_size).max(1);
+        match depth {
+            Some(depth) => depth.min(budget),
+            None => budget.min(MAX_QUEUE_DEPTH),
+        }
+    }
+
+    /// Limits the input is read with when the copy starts.
+    pub fn limits(&self) -> Limits {
+        Limits { rate: self.rate.map(|rate| rate as u64), iops: self.iops.map(|iops| iops as u64) }
+    }
+
+    /// Whether the copy has to go through the throttled reads of the tokio engine.
+    pub fn throttled(&self) -> bool {
+        self.limits().is_limited() || self.control.is_some()
+    }
+
+    pub fn has_conv(&self, conv: Conv) -> bool {
+        self.conv.contains(&conv)
+    }
+
+    pub fn has_iflag(&self, flag: Flag) -> bool {
+        self.iflag.contains(&flag)
+    }
+
+    pub fn has_oflag(&self, flag: Flag) -> bool {
+        self.oflag.contains(&flag)
+    }
+
+    pub fn hash_selection(&self) -> HashSelection {
+        let manifest_format = self.manifest.as_ref().map(|_| self.manifest_format);
+        // a JSON manifest takes whatever was computed, BLAKE2b unless a 512-bit digest was asked for
+        let json_default = manifest_format == Some(ManifestFormat::Json) && !self.blake2b && !self.sha3;
+        HashSelection {
+            blake2b: self.blake2b || manifest_format == Some(ManifestFormat::B2sum) || json_default,
+            sha3: self.sha3 || manifest_format == Some(ManifestFormat::Sha3sum),
+            crc32: self.crc32,
+        }
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::error::DdError;
+
+    fn parse(args: &[&str]) -> Args {
+        Args::parse_from(translate_operands(args.iter().map(OsString::from)))
+    }
+
+    #[test]
+    fn test_dd_operands() {
+        let args = parse(&["ruplica", "if=/dev/zero", "of=out.img", "bs=4M", "count=10", "conv=notrunc,fsync"]);
+        assert_eq!(args.input_file.as_deref(), Some("/dev/zero"));
+        assert_eq!(args.output_file.as_deref(), Some("out.img"));
+        assert_eq!(args.ibs(), 4 << 20);
+        assert_eq!(args.obs(), 4 << 20);
+        assert_eq!(args.count, Some(10));
+        assert_eq!(args.conv, vec![Conv::Notrunc, Conv::Fsync]);
+    }
+
+    #[test]
+    fn test_json_manifest_hashes() {
+        let selection = parse(&["ruplica", "of=x", "--manifest", "x.json", "--manifest-format", "json"]).hash_selection();
+        assert!(selection.blake2b && !selection.sha3);
+        let selection = parse(&["ruplica", "of=x", "--manifest", "x.json", "--manifest-format", "json", "--sha3"]).hash_selection();
+        assert!(!selection.blake2b && selection.sha3);
+    }
+
+    #[test]
+    fn test_queue_depth() {
+        assert_eq!(parse(&["ruplica", "bs=4K"]).queue_depth(), MAX_QUEUE_DEPTH);
+        assert_eq!(parse(&["ruplica", "bs=1M"]).queue_depth(), 64);
+        assert_eq!(parse(&["ruplica", "bs=1G"]).queue_depth(), 1);
+        assert_eq!(parse(&["ruplica", "bs=1M", "--queue-depth", "8", "--buffer-mem", "4M"]).queue_depth(), 4);
+    }
+
+    #[test]
+    fn test_bs_auto() {
+        let args = parse(&["ruplica", "bs=auto", "ibs=4K"]);
+        assert_eq!(args.bs, Some(BlockSize::Auto));
+        assert_eq!((args.block_size(), args.ibs(), args.obs()), (None, 4096, DEFAULT_BLOCK_SIZE));
+    }
+
+    #[test]
+    fn test_throttle_and_priority() {
+        let args = parse(&["ruplica", "--rate", "50M", "iops=200", "--ioprio-class", "idle", "nice=-5"]);
+        assert_eq!(args.limits(), Limits { rate: Some(50 << 20), iops: Some(200) });
+        assert_eq!((args.ioprio_class, args.nice), (Some(IoClass::Idle), Some(-5)));
+        assert!(args.throttled() && !parse(&["ruplica"]).throttled());
+        assert!(Args::try_parse_from(["ruplica", "--ioprio-level", "3"]).is_err());
+        assert!(Args::try_parse_from(["ruplica", "--ioprio-class", "realtime", "--ioprio-level", "8"]).is_err());
+    }
+
+    #[test]
+    fn test_io_flags() {
+        let args = parse(&["ruplica", "if=/dev/sda", "of=x", "iflag=direct,fullblock", "oflag=dsync"]);
+        assert!(args.has_iflag(Flag::Direct) && args.has_iflag(Flag::Fullblock));
+        assert!(args.has_oflag(Flag::Dsync) && !args.has_oflag(Flag::Direct));
+    }
+
+    #[test]
+    fn test_repeated_operands() {
+        let args = parse(&["ruplica", "bs=1K", "bs=4K", "conv=notrunc", "conv=fsync", "--blake2b", "--blake2b"]);
+        assert_eq!((args.ibs(), args.obs()), (4096, 4096));
+        assert_eq!(args.conv, vec![Conv::Notrunc, Conv::Fsync]);
+        assert!(args.blake2b);
+    }
+
+    #[test]
+    fn test_mixed_operands_and_flags() {
+        let args = parse(&["ruplica", "--if", "a=b", "of=x", "--skip", "2x1K", "seek=1b", "--blake2b"]);
+        assert_eq!(args.input_file.as_deref(), Some("a=b"));
+        assert_eq!(args.output_file.as_deref(), Some("x"));
+        assert_eq!(args.skip, 2048);
+        assert_eq!(args.seek, 512);
+        assert!(args.blake2b);
+    }
+
+    #[test]
+    fn test_usage_errors_as_json() {
+        let command_line = translate_operands(["ruplica", "conv=excl,nocreat", "--error-format", "json"].iter().map(OsString::from));
+        assert_eq!(requested_error_format(&command_line), ErrorFormat::Json);
+        let error = DdError::from(Args::create(command_line).unwrap_err());
+        let json: serde_json::Value = serde_json::from_str(&error.to_json()).unwrap();
+        assert_eq!(json["kind"], "usage");
+        assert_eq!(json["exit_code"], 2);
+        assert_eq!(json["message"], "Usage error: cannot combine conv=excl and conv=nocreat");
+
+        // a command line clap rejects still says which format it wants
+        let command_line = translate_operands(["ruplica", "--error-format=json", "--bogus"].iter().map(OsString::from));
+        assert_eq!(requested_error_format(&command_line), ErrorFormat::Json);
+        assert_eq!(DdError::from(Args::create(command_line).unwrap_err()).kind(), crate::io::error::ErrorKind::Usage);
+        assert_eq!(requested_error_format(&[OsString::from("ruplica")]), ErrorFormat::Text);
+    }
+
+    #[test]
+    fn test_unknown_operand_is_rejected() {
+        let translated = translate_operands(["ruplica", "bogus=1"].iter().map(OsString::from));
+        assert_eq!(translated[1], OsString::from("bogus=1"));
+        assert!(Args::try_parse_from(translated).is_err());
+    }
+}
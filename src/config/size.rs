@@ -0,0 +1,96 @@
+/// Multiplier for a GNU dd size suffix: c, w, b, or a unit letter followed by
+/// nothing or `iB` (powers of 1024) or `B` (powers of 1000).
+fn suffix_multiplier(suffix: &str) -> Option<usize> {
+  match suffix {
+    "" | "c" => Some(1),
+    "w" => Some(2),
+    "b" => Some(512),
+    _ => {
+      let mut chars = suffix.chars();
+      let power = "kKMGTPEZY".find(chars.next()?)?.max(1) as u32;
+      let base: usize = match chars.as_str() {
+        "" | "iB" => 1024,
+        "B" => 1000,
+        _ => return None,
+      };
+      base.checked_pow(power)
+    }
+  }
+}
+
+fn parse_factor(value: &str) -> Result<usize, String> {
+  let digits = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+  let (number, suffix) = value.split_at(digits);
+  if number.is_empty() {
+    return Err(format!("invalid number: '{}'", value));
+  }
+  let number: usize = number.parse().map_err(|_| format!("number too large: '{}'", value))?;
+  let multiplier = suffix_multiplier(suffix).ok_or_else(|| format!("invalid suffix in '{}'", value))?;
+  number.checked_mul(multiplier).ok_or_else(|| format!("number too large: '{}'", value))
+}
+
+/// Parses a byte count the way dd does, e.g. `512`, `4K`, `1MB`, `2GiB`, `16b` or `2x4M`.
+pub fn parse_size(value: &str) -> Result<usize, String> {
+  value.split('x').try_fold(1usize, |total, factor| {
+    total.checked_mul(parse_factor(factor)?).ok_or_else(|| format!("number too large: '{}'", value))
+  })
+}
+
+/// `--bs`, a byte count or `auto` to have a probe of the input pick one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockSize {
+  Auto,
+  Bytes(usize),
+}
+
+pub fn parse_block_size(value: &str) -> Result<BlockSize, String> {
+  match value {
+    "auto" => Ok(BlockSize::Auto),
+    _ => parse_size(value).map(BlockSize::Bytes),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_size_suffixes() {
+    let cases = [
+      ("0", 0),
+      ("512", 512),
+      ("7c", 7),
+      ("3w", 6),
+      ("2b", 1024),
+      ("1k", 1024),
+      ("1K", 1024),
+      ("1KiB", 1024),
+      ("1kB", 1000),
+      ("1KB", 1000),
+      ("4M", 4 << 20),
+      ("1MB", 1_000_000),
+      ("1G", 1 << 30),
+      ("1GiB", 1 << 30),
+      ("1T", 1 << 40),
+      ("2x4M", 8 << 20),
+      ("2x3x1K", 6144),
+    ];
+    for (input, expected) in cases {
+      assert_eq!(parse_size(input), Ok(expected), "{}", input);
+    }
+  }
+
+  #[test]
+  fn test_parse_block_size() {
+    assert_eq!(parse_block_size("auto"), Ok(BlockSize::Auto));
+    assert_eq!(parse_block_size("64K"), Ok(BlockSize::Bytes(64 << 10)));
+    assert!(parse_block_size("Auto").is_err());
+  }
+
+  #[test]
+  fn test_parse_size_rejects_garbage() {
+    for input in ["", "M", "4m", "4Q", "4KiBB", "4 K", "x4", "-1", "99999999999999999999", "1Y", "1024Ex1024"] {
+      assert!(parse_size(input).is_err(), "{}", input);
+    }
+  }
+}
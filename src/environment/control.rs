@@ -0,0 +1,77 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::task::JoinHandle;
+
+use crate::config::size::parse_size;
+use crate::environment::progress::human_rate;
+use crate::io::throttle::{Limits, Throttle};
+
+/// Reads limits from `rate=50M` and `iops=200` lines, `#` starts a comment. A limit the file
+/// leaves out, or sets to 0 or `none`, is lifted.
+pub fn parse_limits(text: &str) -> Result<Limits, String> {
+  let mut limits = Limits::default();
+  for line in text.lines() {
+    let line = line.split('#').next().unwrap_or_default().trim();
+    if line.is_empty() {
+      continue;
+    }
+    let (key, value) = line.split_once('=').ok_or_else(|| format!("expected key=value, got '{}'", line))?;
+    let value = match value.trim() {
+      "none" => 0,
+      value => parse_size(value)?,
+    };
+    let limit = (value > 0).then_some(value as u64);
+    match key.trim() {
+      "rate" => limits.rate = limit,
+      "iops" => limits.iops = limit,
+      key => return Err(format!("unknown setting '{}'", key)),
+    }
+  }
+  Ok(limits)
+}
+
+pub fn describe_limits(limits: &Limits) -> String {
+  let rate = limits.rate.map_or("no rate limit".to_string(), |rate| format!("rate {}", human_rate(rate as f64)));
+  let iops = limits.iops.map_or("no IOPS limit".to_string(), |iops| format!("{} IOPS", iops));
+  format!("{}, {}", rate, iops)
+}
+
+fn load(path: &Path) -> Result<Limits, String> {
+  let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+  parse_limits(&text)
+}
+
+/// Installs the SIGHUP handler, then reads `path` again on every SIGHUP and puts its limits on
+/// the throttle. A file that cannot be read or parsed leaves the limits as they were.
+pub fn spawn_control(path: PathBuf, throttle: Arc<Throttle>) -> std::io::Result<JoinHandle<()>> {
+  let mut hangups = signal(SignalKind::hangup())?;
+  Ok(tokio::spawn(async move {
+    while hangups.recv().await.is_some() {
+      match load(&path) {
+        Ok(limits) if limits != throttle.limits() => {
+          throttle.set_limits(limits);
+          eprintln!("ruplica: now reading with {}", describe_limits(&limits));
+        },
+        Ok(_) => {},
+        Err(e) => eprintln!("ruplica: warning: cannot read limits from {}: {}, keeping them", path.display(), e),
+      }
+    }
+  }))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_limits() {
+    let limits = parse_limits("# slow down during business hours\nrate = 20M\niops=100\n").unwrap();
+    assert_eq!(limits, Limits { rate: Some(20 << 20), iops: Some(100) });
+    assert_eq!(parse_limits("rate=none\niops=0").unwrap(), Limits::default());
+    assert_eq!(parse_limits("").unwrap(), Limits::default());
+    assert!(parse_limits("speed=1M").is_err());
+    assert!(parse_limits("rate").is_err());
+  }
+}
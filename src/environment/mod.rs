@@ -1,2 +1,5 @@
 // pub mod memory;
+pub mod control;
+pub mod priority;
+pub mod progress;
 pub mod statistics;
\ No newline at end of file
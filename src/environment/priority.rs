@@ -0,0 +1,92 @@
+use crate::config::IoClass;
+
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+const IOPRIO_CLASS_SHIFT: u32 = 13;
+/// Level the kernel gives best-effort and realtime when none is asked for.
+pub const DEFAULT_IOPRIO_LEVEL: u8 = 4;
+
+fn ioprio_value(class: IoClass, level: u8) -> libc::c_int {
+  let class = match class {
+    IoClass::Realtime => 1,
+    IoClass::BestEffort => 2,
+    // the idle class has no levels
+    IoClass::Idle => return 3 << IOPRIO_CLASS_SHIFT,
+  };
+  (class << IOPRIO_CLASS_SHIFT) | level as libc::c_int
+}
+
+/// Threads of this process. Linux keeps the I/O priority and niceness per thread, the runtime's
+/// threads are already running and the ones started later take the setting of the thread that
+/// starts them.
+fn threads() -> std::io::Result<Vec<libc::pid_t>> {
+  let mut threads = Vec::new();
+  for entry in std::fs::read_dir("/proc/self/task")? {
+    if let Some(thread) = entry?.file_name().to_str().and_then(|name| name.parse().ok()) {
+      threads.push(thread);
+    }
+  }
+  Ok(threads)
+}
+
+fn set_ioprio(thread: libc::pid_t, value: libc::c_int) -> std::io::Result<()> {
+  match unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, thread, value) } {
+    -1 => Err(std::io::Error::last_os_error()),
+    _ => Ok(()),
+  }
+}
+
+fn set_nice(thread: libc::pid_t, nice: i32) -> std::io::Result<()> {
+  match unsafe { libc::setpriority(libc::PRIO_PROCESS, thread as libc::id_t, nice) } {
+    -1 => Err(std::io::Error::last_os_error()),
+    _ => Ok(()),
+  }
+}
+
+/// Sets the I/O scheduling class and level and the niceness of every thread. A setting the
+/// kernel refuses, say the realtime class without CAP_SYS_ADMIN, is warned about and left out.
+pub fn apply(class: Option<IoClass>, level: Option<u8>, nice: Option<i32>) {
+  if class.is_none() && nice.is_none() {
+    return;
+  }
+  let threads = match threads() {
+    Ok(threads) => threads,
+    Err(e) => {
+      eprintln!("ruplica: warning: cannot list the threads to set their priority: {}", e);
+      return;
+    }
+  };
+  if let Some(class) = class {
+    let value = ioprio_value(class, level.unwrap_or(DEFAULT_IOPRIO_LEVEL));
+    if let Err(e) = each_thread(&threads, |thread| set_ioprio(thread, value)) {
+      eprintln!("ruplica: warning: cannot set the I/O priority: {}", e);
+    }
+  }
+  if let Some(nice) = nice {
+    if let Err(e) = each_thread(&threads, |thread| set_nice(thread, nice)) {
+      eprintln!("ruplica: warning: cannot set the nice value: {}", e);
+    }
+  }
+}
+
+/// Runs `set` for every thread, stopping at the first error but for threads that ended meanwhile.
+fn each_thread(threads: &[libc::pid_t], set: impl Fn(libc::pid_t) -> std::io::Result<()>) -> std::io::Result<()> {
+  for thread in threads {
+    match set(*thread) {
+      Err(e) if e.raw_os_error() != Some(libc::ESRCH) => return Err(e),
+      _ => {},
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_ioprio_value() {
+    assert_eq!(ioprio_value(IoClass::BestEffort, 4), 0x4004);
+    assert_eq!(ioprio_value(IoClass::Realtime, 0), 0x2000);
+    assert_eq!(ioprio_value(IoClass::Idle, 7), 0x6000);
+  }
+}
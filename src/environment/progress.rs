@@ -0,0 +1,273 @@
+use std::io::Write;
+use std::sync::Arc;
+use std::time::Duration;
+
+use hifitime::Epoch;
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::{Mutex, Notify};
+use tokio::task::JoinHandle;
+
+use crate::environment::statistics::{DdContext, ReadStatistics, WriteStatistics};
+
+const SI_UNITS: [&str; 7] = ["B", "kB", "MB", "GB", "TB", "PB", "EB"];
+const IEC_UNITS: [&str; 7] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+
+/// Scales `value` to the largest fitting unit, keeping one decimal below `decimals_below`.
+fn scale(value: f64, base: f64, units: &[&str], decimals_below: f64) -> String {
+  let mut value = value;
+  let mut unit = 0;
+  while value >= base && unit < units.len() - 1 {
+    value /= base;
+    unit += 1;
+  }
+  match unit == 0 || value >= decimals_below {
+    true => format!("{:.0} {}", value, units[unit]),
+    false => format!("{:.1} {}", value, units[unit]),
+  }
+}
+
+/// Formats a byte count with an SI unit, the way dd does (`1.5 MB`, `16 MB`).
+pub fn human_bytes(bytes: f64) -> String {
+  scale(bytes, 1000.0, &SI_UNITS, 10.0)
+}
+
+/// Formats a byte count with an IEC unit (`1.5 KiB`).
+pub fn human_bytes_iec(bytes: f64) -> String {
+  scale(bytes, 1024.0, &IEC_UNITS, 10.0)
+}
+
+/// Formats a throughput in bytes per second the way dd does (`71.5 MB/s`, `733 MB/s`).
+pub fn human_rate(bytes_per_second: f64) -> String {
+  match bytes_per_second.is_finite() {
+    true => format!("{}/s", scale(bytes_per_second, 1000.0, &SI_UNITS, 100.0)),
+    false => "Infinity B/s".to_string(),
+  }
+}
+
+/// Formats seconds like printf's `%g`, with six significant digits.
+pub fn human_seconds(seconds: f64) -> String {
+  if seconds <= 0.0 {
+    return "0".to_string();
+  }
+  let decimals = (5 - seconds.log10().floor() as i32).max(0) as usize;
+  let text = format!("{:.*}", decimals, seconds);
+  match text.contains('.') {
+    true => text.trim_end_matches('0').trim_end_matches('.').to_string(),
+    false => text,
+  }
+}
+
+/// Formats a number of seconds as `h:mm:ss`.
+pub fn human_duration(seconds: f64) -> String {
+  let seconds = seconds.max(0.0).round() as u64;
+  format!("{}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
+/// Renders dd's `records in/out` summary, followed by the transfer line when `transfer` is set.
+pub fn summary(read: &ReadStatistics, write: &WriteStatistics, transfer: bool) -> String {
+  let mut out = format!(
+    "{}+{} records in\n{}+{} records out\n",
+    read.full_records, read.partial_records, write.full_records, write.partial_records,
+  );
+  if transfer {
+    let bytes = write.total_bytes_written;
+    let elapsed = (Epoch::now().unwrap() - read.started_at).to_seconds();
+    let copied = match bytes {
+      1 => "1 byte copied".to_string(),
+      0..=999 => format!("{} bytes copied", bytes),
+      _ => format!("{} bytes ({}, {}) copied", bytes, human_bytes(bytes as f64), human_bytes_iec(bytes as f64)),
+    };
+    out += &format!("{}, {} s, {}\n", copied, human_seconds(elapsed), human_rate(bytes as f64 / elapsed));
+  }
+  out
+}
+
+/// Signals that ask for the summary while copying, SIGUSR1 everywhere and SIGINFO where it exists.
+struct InfoSignals {
+  usr1: Signal,
+  #[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd", target_os = "netbsd", target_os = "openbsd", target_os = "dragonfly"))]
+  info: Signal,
+}
+
+impl InfoSignals {
+  fn new() -> std::io::Result<Self> {
+    Ok(InfoSignals {
+      usr1: signal(SignalKind::user_defined1())?,
+      #[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd", target_os = "netbsd", target_os = "openbsd", target_os = "dragonfly"))]
+      info: signal(SignalKind::info())?,
+    })
+  }
+
+  #[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd", target_os = "netbsd", target_os = "openbsd", target_os = "dragonfly"))]
+  async fn recv(&mut self) -> Option<()> {
+    tokio::select! {
+      received = self.usr1.recv() => received,
+      received = self.info.recv() => received,
+    }
+  }
+
+  #[cfg(not(any(target_os = "macos", target_os = "ios", target_os = "freebsd", target_os = "netbsd", target_os = "openbsd", target_os = "dragonfly")))]
+  async fn recv(&mut self) -> Option<()> {
+    self.usr1.recv().await
+  }
+}
+
+/// Prints the summary every time SIGUSR1 (or SIGINFO) arrives, like `kill -USR1 $(pidof dd)`.
+/// With `quiet` (status=none) the signals are only caught, so they cannot end the copy.
+pub fn spawn_signal_reporter(dd_context: &DdContext, quiet: bool, transfer: bool) -> std::io::Result<JoinHandle<()>> {
+  let mut signals = InfoSignals::new()?;
+  let read_statistics = dd_context.read_statistics.clone();
+  let write_statistics = dd_context.write_statistics.clone();
+  Ok(tokio::spawn(async move {
+    while signals.recv().await.is_some() {
+      if quiet {
+        continue;
+      }
+      let read = read_statistics.lock().await.clone();
+      let write = write_statistics.lock().await.clone();
+      eprint!("{}", summary(&read, &write, transfer));
+    }
+  }))
+}
+
+/// Periodically rewrites one stderr line with bytes copied, percentage, throughput and ETA.
+pub struct ProgressReporter {
+  read_statistics: Arc<Mutex<ReadStatistics>>,
+  write_statistics: Arc<Mutex<WriteStatistics>>,
+  last_bytes: u64,
+  last_at: Epoch,
+}
+
+impl ProgressReporter {
+  pub fn new(dd_context: &DdContext) -> Self {
+    ProgressReporter {
+      read_statistics: dd_context.read_statistics.clone(),
+      write_statistics: dd_context.write_statistics.clone(),
+      last_bytes: 0,
+      last_at: Epoch::now().unwrap(),
+    }
+  }
+
+  /// Builds the status line and remembers the sample for the next current-rate calculation.
+  pub async fn line(&mut self) -> String {
+    let estimated = self.read_statistics.lock().await.estimated_bytes;
+    let (bytes, started_at) = {
+      let statistics = self.write_statistics.lock().await;
+      (statistics.total_bytes_written, statistics.started_at)
+    };
+    let now = Epoch::now().unwrap();
+    let elapsed = (now - started_at).to_seconds();
+    let interval = (now - self.last_at).to_seconds();
+    let average = if elapsed > 0.0 { bytes as f64 / elapsed } else { 0.0 };
+    let current = if interval > 0.0 { bytes.saturating_sub(self.last_bytes) as f64 / interval } else { 0.0 };
+    self.last_bytes = bytes;
+    self.last_at = now;
+
+    let mut line = format!("{} bytes ({}) copied", bytes, human_bytes(bytes as f64));
+    if estimated > 0 {
+      line += &format!(" of {}, {:.1}%", human_bytes(estimated as f64), bytes as f64 * 100.0 / estimated as f64);
+    }
+    line += &format!(
+      ", {}, {} now, {} avg",
+      human_duration(elapsed),
+      human_rate(current),
+      human_rate(average),
+    );
+    if estimated > bytes && average > 0.0 {
+      line += &format!(", ETA {}", human_duration((estimated - bytes) as f64 / average));
+    }
+    line
+  }
+
+  async fn print(&mut self) {
+    let line = self.line().await;
+    // \r and "erase to end of line" keep the report on a single line
+    eprint!("\r{}\x1b[K", line);
+    let _ = std::io::stderr().flush();
+  }
+
+  /// Prints a line every `interval` until `stop` is notified, then prints a last one and a newline.
+  pub fn spawn(mut self, interval: Duration, stop: Arc<Notify>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+      let mut ticker = tokio::time::interval(interval);
+      loop {
+        tokio::select! {
+          _ = ticker.tick() => self.print().await,
+          _ = stop.notified() => break,
+        }
+      }
+      self.print().await;
+      eprintln!();
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_human_bytes() {
+    assert_eq!(human_bytes(512.0), "512 B");
+    assert_eq!(human_bytes(1500.0), "1.5 kB");
+    assert_eq!(human_bytes(3_000_000.0), "3.0 MB");
+    assert_eq!(human_bytes(16_300_000.0), "16 MB");
+    assert_eq!(human_bytes(2.5e12), "2.5 TB");
+    assert_eq!(human_bytes_iec(1_073_741_824.0), "1.0 GiB");
+    assert_eq!(human_rate(71_500_000.0), "71.5 MB/s");
+    assert_eq!(human_rate(733_000_000.0), "733 MB/s");
+  }
+
+  #[test]
+  fn test_human_seconds() {
+    assert_eq!(human_seconds(1.4654623), "1.46546");
+    assert_eq!(human_seconds(0.00213), "0.00213");
+    assert_eq!(human_seconds(15.0), "15");
+  }
+
+  #[test]
+  fn test_summary() {
+    let mut read = ReadStatistics::new();
+    let mut write = WriteStatistics::new();
+    read.init();
+    write.init();
+    for _ in 0..3 {
+      read.add_read(512, true);
+      write.add_write(512, true);
+    }
+    read.add_read(464, false);
+    write.add_write(464, false);
+    let text = summary(&read, &write, true);
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!(lines[0], "3+1 records in");
+    assert_eq!(lines[1], "3+1 records out");
+    assert!(lines[2].starts_with("2000 bytes (2.0 kB, 2.0 KiB) copied, "), "{}", lines[2]);
+    assert_eq!(summary(&read, &write, false).lines().count(), 2);
+    read.peak_memory = 2048;
+    read.queue_stalls = 3;
+    write.hole_bytes = 1024;
+    read.rescued_bytes = 512;
+    read.lost_bytes = 512;
+    // scripts parse this like dd's, the buffer, hole and rescue figures are only logged
+    assert_eq!(summary(&read, &write, true).lines().count(), 3);
+    assert_eq!(summary(&read, &write, false).lines().count(), 2);
+  }
+
+  #[test]
+  fn test_human_duration() {
+    assert_eq!(human_duration(0.2), "0:00:00");
+    assert_eq!(human_duration(61.0), "0:01:01");
+    assert_eq!(human_duration(3725.0), "1:02:05");
+  }
+
+  #[tokio::test]
+  async fn test_progress_line() {
+    let context = DdContext::new();
+    context.read_statistics.lock().await.estimated_bytes = 4000;
+    context.write_statistics.lock().await.init();
+    context.write_statistics.lock().await.add_write(1000, true);
+    let mut reporter = ProgressReporter::new(&context);
+    let line = reporter.line().await;
+    assert!(line.starts_with("1000 bytes (1.0 kB) copied of 4.0 kB, 25.0%"), "{}", line);
+  }
+}
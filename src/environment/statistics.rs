@@ -2,6 +2,13 @@ use std::{collections::HashMap, sync::Arc};
 use tokio::sync::{Mutex, Notify};
 use hifitime::prelude::*;
 
+use crate::environment::progress;
+use crate::io::buffers::BufferPool;
+use crate::io::digest::Digests;
+use crate::io::error::Failure;
+use crate::io::sink::verify::VerifyReport;
+use crate::io::throttle::Throttle;
+
 #[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize, derivative::Derivative)]
 #[derivative(Default(new="true"))]
 pub struct WriteStatistics {
@@ -10,6 +17,9 @@ pub struct WriteStatistics {
   pub last_write_at: hifitime::Epoch,
   pub total_writes: u64,
   pub total_errors: u64,
+  pub full_records: u64,      // writes of a whole output block
+  pub partial_records: u64,   // short writes, usually the tail
+  pub hole_bytes: u64,        // zero bytes seeked over instead of written, conv=sparse
 }
 
 
@@ -21,6 +31,15 @@ pub struct ReadStatistics {
   pub last_read_at: hifitime::Epoch,
   pub total_reads: u64,
   pub total_errors: u64,
+  pub full_records: u64,      // reads that filled a whole input block
+  pub partial_records: u64,   // blocks that came in short, usually the tail
+  pub short_reads: u64,       // reads that returned less than asked, fullblock joins them into blocks
+  pub estimated_bytes: u64,   // expected input size, 0 when unknown
+  pub rescued_bytes: u64,     // rescue mode only, input bytes read so far over all runs
+  pub lost_bytes: u64,        // rescue mode only, input bytes still unread
+  pub hole_bytes: u64,        // input bytes in holes that were not read, conv=sparse
+  pub queue_stalls: u64,      // blocks that had to wait for room in the queue to the sink
+  pub peak_memory: u64,       // most bytes block buffers took at once
 }
 
 #[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
@@ -57,6 +76,14 @@ pub struct DdContext {
   pub main_notifications: Arc<Notify>,
   pub sink_notifications: Arc<Notify>,
   pub source_notifications: Arc<Notify>,
+  pub source_digests: Arc<Mutex<Digests>>,
+  pub sink_digests: Arc<Mutex<Digests>>,
+  pub job_id: uuid::Uuid,
+  pub verify_report: Arc<Mutex<Option<VerifyReport>>>,
+  pub failure: Arc<Mutex<Option<Failure>>>,   // first error that stopped a task
+  pub source_changed: Arc<Mutex<Option<String>>>, // what changed about the input while it was read
+  pub buffers: Arc<BufferPool>,   // block buffers the sink hands back to the source
+  pub throttle: Arc<Throttle>,    // paces the reads, --rate and --iops
 }
 
 
@@ -69,7 +96,14 @@ impl DdContext {
       main_notifications: Arc::new(Notify::new()),
       source_notifications: Arc::new(Notify::new()),
       sink_notifications: Arc::new(Notify::new()),
-
+      source_digests: Arc::new(Mutex::new(Digests::default())),
+      sink_digests: Arc::new(Mutex::new(Digests::default())),
+      job_id: uuid::Uuid::now_v7(),
+      verify_report: Arc::new(Mutex::new(None)),
+      failure: Arc::new(Mutex::new(None)),
+      source_changed: Arc::new(Mutex::new(None)),
+      buffers: Arc::new(BufferPool::new()),
+      throttle: Arc::new(Throttle::default()),
     }
   }
 
@@ -98,17 +132,88 @@ impl DdContext {
     false
   }
 
-  pub async fn display_statistics(&self) {
+  /// Prints the dd style records and transfer summary, the transfer line only when `transfer` is set.
+  pub async fn display_statistics(&self, transfer: bool) {
     let read_statistics = self.read_statistics.lock().await;
     let write_statistics = self.write_statistics.lock().await;
-    println!("Read Statistics: {}", serde_json::to_string_pretty(&*read_statistics).unwrap());
-    println!("Write Statistics: {}", serde_json::to_string_pretty(&*write_statistics).unwrap());
+    tracing::debug!("Read Statistics: {}", serde_json::to_string(&*read_statistics).unwrap());
+    tracing::debug!("Write Statistics: {}", serde_json::to_string(&*write_statistics).unwrap());
+    // only copies through the block queue have these
+    if read_statistics.peak_memory > 0 {
+      tracing::info!("Buffers: peak {} bytes, queue full {} times", read_statistics.peak_memory, read_statistics.queue_stalls);
+    }
+    if read_statistics.hole_bytes + write_statistics.hole_bytes > 0 {
+      tracing::info!("Holes: {} bytes not read, {} bytes not written", read_statistics.hole_bytes, write_statistics.hole_bytes);
+    }
+    if read_statistics.rescued_bytes + read_statistics.lost_bytes > 0 {
+      tracing::info!("Rescue: {} bytes rescued, {} bytes lost", read_statistics.rescued_bytes, read_statistics.lost_bytes);
+    }
+    eprint!("{}", progress::summary(&read_statistics, &write_statistics, transfer));
+  }
+
+  /// Prints source and sink digests side by side, returns names of the algorithms that differ.
+  pub async fn display_digests(&self) -> Vec<&'static str> {
+    let source = self.source_digests.lock().await;
+    let sink = self.sink_digests.lock().await;
+    if source.is_empty() && sink.is_empty() {
+      return Vec::new();
+    }
+    let mismatches = source.mismatches(&sink);
+    eprintln!("Digest report:");
+    for ((name, source_digest), (_, sink_digest)) in source.entries().into_iter().zip(sink.entries()) {
+      if source_digest.is_none() && sink_digest.is_none() {
+        continue;
+      }
+      let verdict = if mismatches.contains(&name) { "MISMATCH" } else { "OK" };
+      eprintln!("  {:<8} source: {}", name, source_digest.unwrap_or("-"));
+      eprintln!("  {:<8} sink:   {}  {}", "", sink_digest.unwrap_or("-"), verdict);
+    }
+    mismatches
+  }
+
+  /// Prints the read-back verification outcome, returns a description of the failure if there was one.
+  pub async fn display_verification(&self) -> Option<String> {
+    let report = self.verify_report.lock().await;
+    let Some(report) = report.as_ref() else {
+      eprintln!("Verification: read-back did not complete");
+      return Some("read-back did not complete".to_string());
+    };
+    let source = self.source_digests.lock().await;
+    let mismatches = source.mismatches(&report.digests);
+    eprintln!(
+      "Verification: {} bytes read back at offset {}{}",
+      report.bytes_verified,
+      report.offset,
+      if report.direct_io { " (direct I/O)" } else { " (page cache dropped)" },
+    );
+    match (report.first_mismatch, mismatches.is_empty()) {
+      (Some(offset), _) => {
+        eprintln!("Verification: FAILED, first mismatch at offset {}", offset);
+        Some(format!("first mismatch at offset {}", offset))
+      },
+      (None, false) => {
+        eprintln!("Verification: FAILED, {} differ from the source", mismatches.join(", "));
+        Some(format!("{} differ from the source", mismatches.join(", ")))
+      },
+      (None, true) => {
+        eprintln!("Verification: OK");
+        None
+      }
+    }
+  }
+
+  /// Keeps the first error reported, later ones are usually consequences of it.
+  pub async fn record_failure(failure: &Mutex<Option<Failure>>, error: Failure) {
+    let mut failure = failure.lock().await;
+    if failure.is_none() {
+      *failure = Some(error);
+    }
   }
 
   pub async fn display_tasks(&self) {
     for (name, task) in self.task_status.iter() {
       let task = task.lock().await;
-      println!("Task: {}, Status: {:?}", name, task.status);
+      tracing::debug!("Task: {}, Status: {:?}", name, task.status);
     }
   }
 }
@@ -126,14 +231,39 @@ impl ReadStatistics {
     self.last_read_at = Epoch::now().unwrap();
     self.total_reads = 0;
     self.total_errors = 0;
+    self.full_records = 0;
+    self.partial_records = 0;
+    self.short_reads = 0;
+    self.estimated_bytes = 0;
+    self.rescued_bytes = 0;
+    self.lost_bytes = 0;
+    self.hole_bytes = 0;
+    self.queue_stalls = 0;
+    self.peak_memory = 0;
   }
 
-  pub fn add_read(&mut self, bytes_read: u64) {
+  pub fn add_read(&mut self, bytes_read: u64, full: bool) {
     self.total_reads += 1;
+    match full {
+      true => self.full_records += 1,
+      false => self.partial_records += 1,
+    }
     self.total_bytes_read += bytes_read;
     self.last_read_at = Epoch::now().unwrap();
   }
 
+  /// Counts bytes the zero-copy path moved, records are derived at the end by `count_records`.
+  pub fn add_bytes(&mut self, bytes_read: u64) {
+    self.total_reads += 1;
+    self.total_bytes_read += bytes_read;
+    self.last_read_at = Epoch::now().unwrap();
+  }
+
+  pub fn count_records(&mut self, block_size: u64) {
+    self.full_records = self.total_bytes_read / block_size;
+    self.partial_records = u64::from(!self.total_bytes_read.is_multiple_of(block_size));
+  }
+
   pub fn add_error(&mut self) {
     self.total_errors += 1;
   }
@@ -146,14 +276,33 @@ impl WriteStatistics {
     self.last_write_at = Epoch::now().unwrap();
     self.total_writes = 0;
     self.total_errors = 0;
+    self.full_records = 0;
+    self.partial_records = 0;
+    self.hole_bytes = 0;
   }
   
-  pub fn add_write(&mut self, bytes_written: u64) {
+  pub fn add_write(&mut self, bytes_written: u64, full: bool) {
+    self.total_writes += 1;
+    match full {
+      true => self.full_records += 1,
+      false => self.partial_records += 1,
+    }
+    self.total_bytes_written += bytes_written;
+    self.last_write_at = Epoch::now().unwrap();
+  }
+
+  /// Counts bytes the zero-copy path moved, records are derived at the end by `count_records`.
+  pub fn add_bytes(&mut self, bytes_written: u64) {
     self.total_writes += 1;
     self.total_bytes_written += bytes_written;
     self.last_write_at = Epoch::now().unwrap();
   }
 
+  pub fn count_records(&mut self, block_size: u64) {
+    self.full_records = self.total_bytes_written / block_size;
+    self.partial_records = u64::from(!self.total_bytes_written.is_multiple_of(block_size));
+  }
+
   pub fn add_error(&mut self) {
     self.total_errors += 1;
   }
@@ -167,11 +316,9 @@ impl Task {
   }
 
   pub fn update_worktime(&mut self) {
-    if self.completed_at.is_none() {
-      self.worktime = self.last_updated_at - self.started_at
-    } else {
-      // safe_unwrap
-      self.worktime = self.completed_at.unwrap() - self.started_at
+    match self.completed_at {
+      None => self.worktime = self.last_updated_at - self.started_at,
+      Some(completed_at) => self.worktime = completed_at - self.started_at,
     }
   }
 
@@ -191,18 +338,18 @@ impl Task {
     });
   }
 
-  pub fn fail(&mut self, return_code: i64) {
+  pub fn fail(&mut self, return_code: i64, message: String) {
     self.completed_at = Some(Epoch::now().unwrap());
     self.ping();
 
     self.status = TaskStatus::Failed(TaskStatusMessage {
       code: return_code,
-      message: "Task failed".to_string(),
+      message,
     });
   }
 
   pub fn display(&mut self) {
-    println!("{}", serde_json::to_string_pretty(self).unwrap());
+    tracing::debug!("{}", serde_json::to_string_pretty(self).unwrap());
   }
 
 
@@ -0,0 +1,85 @@
+use std::alloc::Layout;
+use std::ptr::NonNull;
+
+use bytes::{Buf, BytesMut};
+
+/// Alignment that satisfies O_DIRECT on every device we care about.
+pub const DIRECT_ALIGNMENT: usize = 4096;
+
+/// Zero-initialized heap buffer whose start address is aligned to `align`.
+pub struct AlignedBuffer {
+  ptr: NonNull<u8>,
+  layout: Layout,
+}
+
+// the buffer owns its allocation exclusively, like a Vec<u8>
+unsafe impl Send for AlignedBuffer {}
+unsafe impl Sync for AlignedBuffer {}
+
+impl AlignedBuffer {
+  pub fn new(size: usize, align: usize) -> Self {
+    let layout = Layout::from_size_align(size.max(1), align).expect("alignment must be a power of two");
+    // safety: the layout has a non-zero size
+    let ptr = unsafe { std::alloc::alloc_zeroed(layout) };
+    let ptr = NonNull::new(ptr).unwrap_or_else(|| std::alloc::handle_alloc_error(layout));
+    AlignedBuffer { ptr, layout }
+  }
+
+  pub fn len(&self) -> usize {
+    self.layout.size()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  pub fn as_slice(&self) -> &[u8] {
+    // safety: ptr is valid for layout.size() initialized bytes for the lifetime of self
+    unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.layout.size()) }
+  }
+
+  pub fn as_mut_slice(&mut self) -> &mut [u8] {
+    // safety: as above, and &mut self guarantees exclusive access
+    unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.layout.size()) }
+  }
+}
+
+impl Drop for AlignedBuffer {
+  fn drop(&mut self) {
+    // safety: allocated in new() with the same layout
+    unsafe { std::alloc::dealloc(self.ptr.as_ptr(), self.layout) }
+  }
+}
+
+/// Empty `BytesMut` with room for `size` bytes that start at a multiple of `align`, so a block
+/// can be read into it with O_DIRECT and still go through the pipeline like any other.
+pub fn aligned_bytes(size: usize, align: usize) -> BytesMut {
+  let mut bytes = BytesMut::zeroed(size + align);
+  let padding = bytes.as_ptr().align_offset(align);
+  bytes.advance(padding);
+  bytes.clear();
+  bytes
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_aligned_buffer() {
+    let mut buffer = AlignedBuffer::new(3 * DIRECT_ALIGNMENT, DIRECT_ALIGNMENT);
+    assert_eq!(buffer.as_slice().as_ptr() as usize % DIRECT_ALIGNMENT, 0);
+    assert_eq!(buffer.len(), 3 * DIRECT_ALIGNMENT);
+    assert!(buffer.as_slice().iter().all(|b| *b == 0));
+    buffer.as_mut_slice()[5] = 1;
+    assert_eq!(buffer.as_slice()[5], 1);
+  }
+
+  #[test]
+  fn test_aligned_bytes() {
+    let mut bytes = aligned_bytes(10_000, DIRECT_ALIGNMENT);
+    assert!(bytes.is_empty() && bytes.capacity() >= 10_000);
+    bytes.resize(10_000, 0);
+    assert_eq!(bytes.as_ptr() as usize % DIRECT_ALIGNMENT, 0);
+  }
+}
@@ -0,0 +1,94 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use bytes::BytesMut;
+
+use crate::io::aligned::aligned_bytes;
+
+/// Block buffers the sink is done with, handed back to the source for its next reads instead of
+/// allocating one per block. Also keeps track of how much memory the blocks take.
+#[derive(Debug, Default)]
+pub struct BufferPool {
+  free: Mutex<Vec<BytesMut>>,
+  // size of the last buffer asked for, smaller ones are not worth keeping
+  block_size: AtomicUsize,
+  // bytes sent to the sink and not written yet
+  in_flight: AtomicU64,
+  // bytes of the buffers kept in `free`
+  kept: AtomicU64,
+  peak: AtomicU64,
+}
+
+impl BufferPool {
+  pub fn new() -> Self {
+    BufferPool::default()
+  }
+
+  /// An empty buffer with room for `size` bytes, starting at a multiple of `align` if given.
+  pub fn take(&self, size: usize, align: Option<usize>) -> BytesMut {
+    self.block_size.store(size, Ordering::Relaxed);
+    let reused = {
+      let mut free = self.free.lock().unwrap();
+      let fits = |buffer: &BytesMut| buffer.capacity() >= size && align.is_none_or(|align| (buffer.as_ptr() as usize).is_multiple_of(align));
+      free.iter().rposition(fits).map(|index| free.swap_remove(index))
+    };
+    match (reused, align) {
+      (Some(buffer), _) => {
+        self.kept.fetch_sub(buffer.capacity() as u64, Ordering::Relaxed);
+        buffer
+      },
+      (None, Some(align)) => aligned_bytes(size, align),
+      (None, None) => BytesMut::with_capacity(size),
+    }
+  }
+
+  /// Takes back a buffer once its data was written.
+  pub fn give(&self, mut buffer: BytesMut) {
+    // pieces the reblocker split off a larger block are left to be freed
+    if buffer.capacity() < self.block_size.load(Ordering::Relaxed) {
+      return;
+    }
+    buffer.clear();
+    self.kept.fetch_add(buffer.capacity() as u64, Ordering::Relaxed);
+    self.free.lock().unwrap().push(buffer);
+  }
+
+  /// Counts `bytes` sent on to the sink.
+  pub fn hold(&self, bytes: usize) {
+    let in_flight = self.in_flight.fetch_add(bytes as u64, Ordering::Relaxed) + bytes as u64;
+    self.peak.fetch_max(in_flight + self.kept.load(Ordering::Relaxed), Ordering::Relaxed);
+  }
+
+  /// Counts `bytes` the sink has written.
+  pub fn release(&self, bytes: usize) {
+    let _ = self.in_flight.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |in_flight| Some(in_flight.saturating_sub(bytes as u64)));
+  }
+
+  /// Most bytes block buffers took at once, sent on or kept for reuse.
+  pub fn peak(&self) -> u64 {
+    self.peak.load(Ordering::Relaxed)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_pool_reuses_buffers() {
+    let pool = BufferPool::new();
+    let mut buffer = pool.take(4096, Some(4096));
+    buffer.extend_from_slice(&[1u8; 4096]);
+    let address = buffer.as_ptr();
+    pool.hold(buffer.len());
+    pool.release(buffer.len());
+    pool.give(buffer);
+    let buffer = pool.take(4096, Some(4096));
+    assert_eq!((buffer.as_ptr(), buffer.len()), (address, 0));
+    assert_eq!(pool.peak(), 4096);
+
+    // too small for the blocks read now
+    pool.give(BytesMut::with_capacity(100));
+    assert_ne!(pool.take(4096, None).capacity(), 100);
+  }
+}
@@ -1,6 +1,94 @@
+use std::collections::BTreeMap;
 
-pub struct chunk {
-    pub data: Vec<u8>,
-    pub offset: u64,
-    pub length: u64,
-}
\ No newline at end of file
+use bytes::BytesMut;
+
+/// A piece of the copy as it travels from the source to the sink. `offset` counts from the
+/// first byte of the output data, so chunks read in parallel can be written and hashed at the
+/// right place whatever order they arrive in. An empty chunk marks the end of the input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+  pub data: BytesMut,
+  pub offset: u64,
+}
+
+impl Chunk {
+  pub fn new(data: BytesMut, offset: u64) -> Self {
+    Chunk { data, offset }
+  }
+
+  pub fn end(offset: u64) -> Self {
+    Chunk { data: BytesMut::new(), offset }
+  }
+
+  pub fn is_end(&self) -> bool {
+    self.data.is_empty()
+  }
+
+  pub fn length(&self) -> u64 {
+    self.data.len() as u64
+  }
+}
+
+/// Holds back chunks that arrive ahead of their turn and hands them out in offset order, for
+/// whatever has to see the data in sequence: hashes, streams, the journal.
+#[derive(Debug, Default)]
+pub struct Reorder {
+  // offset of the chunk that is released next
+  next: u64,
+  pending: BTreeMap<u64, BytesMut>,
+  pending_bytes: usize,
+}
+
+impl Reorder {
+  pub fn new(start: u64) -> Self {
+    Reorder { next: start, ..Reorder::default() }
+  }
+
+  /// Takes `chunk` and returns the chunks that now follow on what was released before.
+  pub fn push(&mut self, chunk: Chunk) -> Vec<Chunk> {
+    if chunk.offset != self.next {
+      self.pending_bytes += chunk.data.len();
+      self.pending.insert(chunk.offset, chunk.data);
+      return Vec::new();
+    }
+    self.next += chunk.length();
+    let mut ready = vec![chunk];
+    while let Some(data) = self.pending.remove(&self.next) {
+      self.pending_bytes -= data.len();
+      ready.push(Chunk::new(data, self.next));
+      self.next += ready.last().unwrap().length();
+    }
+    ready
+  }
+
+  /// Offset the next released chunk starts at.
+  pub fn next(&self) -> u64 {
+    self.next
+  }
+
+  /// Bytes held back, waiting for an earlier chunk.
+  pub fn pending_bytes(&self) -> usize {
+    self.pending_bytes
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn chunk(data: &[u8], offset: u64) -> Chunk {
+    Chunk::new(BytesMut::from(data), offset)
+  }
+
+  #[test]
+  fn test_reorder_releases_in_sequence() {
+    let mut reorder = Reorder::new(0);
+    assert!(reorder.push(chunk(b"cd", 2)).is_empty());
+    assert!(reorder.push(chunk(b"efg", 4)).is_empty());
+    assert_eq!(reorder.pending_bytes(), 5);
+    let ready = reorder.push(chunk(b"ab", 0));
+    assert_eq!(ready, vec![chunk(b"ab", 0), chunk(b"cd", 2), chunk(b"efg", 4)]);
+    assert_eq!((reorder.next(), reorder.pending_bytes()), (7, 0));
+    assert_eq!(reorder.push(chunk(b"h", 7)), vec![chunk(b"h", 7)]);
+  }
+}
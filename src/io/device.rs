@@ -0,0 +1,218 @@
+use std::io::SeekFrom;
+use std::os::fd::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf};
+
+/// Anything `DataSource` can read from.
+pub trait SourceDevice: AsyncRead + AsyncSeek + AsRawFd + Unpin + Send {}
+impl<T: AsyncRead + AsyncSeek + AsRawFd + Unpin + Send> SourceDevice for T {}
+
+/// Anything `DataSink` can write to.
+pub trait SinkDevice: AsyncWrite + AsyncSeek + AsRawFd + Unpin + Send {}
+impl<T: AsyncWrite + AsyncSeek + AsRawFd + Unpin + Send> SinkDevice for T {}
+
+/// Adapter for pipes, sockets and terminals, seeking on it fails with `Unsupported`.
+#[derive(Debug)]
+pub struct Stream<T>(pub T);
+
+impl<T: AsyncRead + Unpin> AsyncRead for Stream<T> {
+  fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+    Pin::new(&mut self.0).poll_read(cx, buf)
+  }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Stream<T> {
+  fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+    Pin::new(&mut self.0).poll_write(cx, buf)
+  }
+
+  fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+    Pin::new(&mut self.0).poll_flush(cx)
+  }
+
+  fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+    Pin::new(&mut self.0).poll_shutdown(cx)
+  }
+}
+
+impl<T: Unpin> AsyncSeek for Stream<T> {
+  fn start_seek(self: Pin<&mut Self>, _position: SeekFrom) -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "stream is not seekable"))
+  }
+
+  fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<u64>> {
+    Poll::Ready(Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "stream is not seekable")))
+  }
+}
+
+impl<T: AsRawFd> AsRawFd for Stream<T> {
+  fn as_raw_fd(&self) -> RawFd {
+    self.0.as_raw_fd()
+  }
+}
+
+/// True for seek errors that just mean the device is a stream.
+pub fn is_unseekable(error: &std::io::Error) -> bool {
+  error.kind() == std::io::ErrorKind::Unsupported || error.raw_os_error() == Some(libc::ESPIPE)
+}
+
+fn cvt(result: libc::c_int) -> std::io::Result<()> {
+  match result {
+    -1 => Err(std::io::Error::last_os_error()),
+    _ => Ok(()),
+  }
+}
+
+pub fn fsync(fd: RawFd) -> std::io::Result<()> {
+  cvt(unsafe { libc::fsync(fd) })
+}
+
+pub fn fdatasync(fd: RawFd) -> std::io::Result<()> {
+  cvt(unsafe { libc::fdatasync(fd) })
+}
+
+/// Grows the file behind `fd` to `length` bytes, never shrinks it.
+pub fn extend_to(fd: RawFd, length: u64) -> std::io::Result<()> {
+  let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+  cvt(unsafe { libc::fstat(fd, &mut stat) })?;
+  if (stat.st_size as u64) < length {
+    cvt(unsafe { libc::ftruncate(fd, length as libc::off_t) })?;
+  }
+  Ok(())
+}
+
+/// Flags for `custom_flags` that give direct, dsync and sync I/O.
+pub fn open_flags(direct: bool, dsync: bool, sync: bool) -> i32 {
+  let mut flags = 0;
+  if direct {
+    flags |= libc::O_DIRECT;
+  }
+  if dsync {
+    flags |= libc::O_DSYNC;
+  }
+  if sync {
+    flags |= libc::O_SYNC;
+  }
+  flags
+}
+
+/// Turns O_DIRECT on or off for an open file.
+pub fn set_direct(fd: RawFd, direct: bool) -> std::io::Result<()> {
+  let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+  if flags == -1 {
+    return Err(std::io::Error::last_os_error());
+  }
+  let flags = match direct {
+    true => flags | libc::O_DIRECT,
+    false => flags & !libc::O_DIRECT,
+  };
+  cvt(unsafe { libc::fcntl(fd, libc::F_SETFL, flags) })
+}
+
+/// Regular files and block devices can be read and written at any offset, other files only in order.
+pub fn is_positional(fd: RawFd) -> bool {
+  let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+  if cvt(unsafe { libc::fstat(fd, &mut stat) }).is_err() {
+    return false;
+  }
+  matches!(stat.st_mode & libc::S_IFMT, libc::S_IFREG | libc::S_IFBLK)
+}
+
+/// Size of a file or block device, stat reports 0 for the latter. Moves the file offset.
+pub fn file_size(fd: RawFd) -> std::io::Result<u64> {
+  match unsafe { libc::lseek(fd, 0, libc::SEEK_END) } {
+    -1 => Err(std::io::Error::last_os_error()),
+    size => Ok(size as u64),
+  }
+}
+
+/// Size direct I/O has to be a multiple of: what a block device reports, 512 bytes for files.
+pub fn logical_block_size(fd: RawFd) -> std::io::Result<usize> {
+  let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+  cvt(unsafe { libc::fstat(fd, &mut stat) })?;
+  if stat.st_mode & libc::S_IFMT != libc::S_IFBLK {
+    return Ok(512);
+  }
+  let mut size: libc::c_int = 0;
+  cvt(unsafe { libc::ioctl(fd, libc::BLKSSZGET, &mut size) })?;
+  Ok(size as usize)
+}
+
+/// Asks the kernel to drop cached pages of a range, `length` 0 meaning up to the end.
+/// Best effort: dirty pages stay until they were written back.
+pub fn drop_cache(fd: RawFd, offset: u64, length: u64) {
+  unsafe {
+    libc::posix_fadvise(fd, offset as libc::off_t, length as libc::off_t, libc::POSIX_FADV_DONTNEED);
+  }
+}
+
+/// Finds the first data extent of the file behind `fd` at or after `offset`, as `(start, end)`.
+/// None when only a hole is left. Moves the file offset.
+pub fn next_data(fd: RawFd, offset: u64) -> std::io::Result<Option<(u64, u64)>> {
+  let start = unsafe { libc::lseek(fd, offset as libc::off_t, libc::SEEK_DATA) };
+  if start == -1 {
+    let error = std::io::Error::last_os_error();
+    return match error.raw_os_error() {
+      Some(libc::ENXIO) => Ok(None),
+      _ => Err(error),
+    };
+  }
+  let end = unsafe { libc::lseek(fd, start, libc::SEEK_HOLE) };
+  if end == -1 {
+    return Err(std::io::Error::last_os_error());
+  }
+  Ok(Some((start as u64, end as u64)))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tokio::io::AsyncSeekExt;
+
+  #[tokio::test]
+  async fn test_stream_is_not_seekable() {
+    let file = tokio::fs::File::open("Cargo.toml").await.unwrap();
+    let mut stream = Stream(file);
+    let error = stream.seek(SeekFrom::Start(10)).await.unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::Unsupported);
+    assert!(is_unseekable(&error));
+    assert!(is_unseekable(&std::io::Error::from_raw_os_error(libc::ESPIPE)));
+    assert!(!is_unseekable(&std::io::Error::from_raw_os_error(libc::EIO)));
+  }
+
+  #[test]
+  fn test_extend_to_never_shrinks() {
+    let file = tempfile::tempfile().unwrap();
+    extend_to(file.as_raw_fd(), 100).unwrap();
+    assert_eq!(file.metadata().unwrap().len(), 100);
+    extend_to(file.as_raw_fd(), 10).unwrap();
+    assert_eq!(file.metadata().unwrap().len(), 100);
+  }
+
+  #[test]
+  fn test_direct_flag_and_block_size() {
+    let file = tempfile::tempfile().unwrap();
+    assert_eq!(logical_block_size(file.as_raw_fd()).unwrap(), 512);
+    // not every filesystem supports O_DIRECT, turning it off always works
+    let _ = set_direct(file.as_raw_fd(), true);
+    set_direct(file.as_raw_fd(), false).unwrap();
+    assert_eq!(unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) } & libc::O_DIRECT, 0);
+    assert_eq!(open_flags(true, false, true), libc::O_DIRECT | libc::O_SYNC);
+  }
+
+  #[test]
+  fn test_next_data_finds_extents() {
+    use std::os::unix::fs::FileExt;
+    let file = tempfile::tempfile().unwrap();
+    file.write_all_at(&[1u8; 4096], 1 << 20).unwrap();
+    file.set_len(2 << 20).unwrap();
+    let (start, end) = next_data(file.as_raw_fd(), 0).unwrap().unwrap();
+    // filesystems without hole support report the whole file as data
+    assert!(start <= 1 << 20 && end >= (1 << 20) + 4096);
+    if start > 0 {
+      assert_eq!(next_data(file.as_raw_fd(), end).unwrap(), None);
+    }
+  }
+}
@@ -0,0 +1,131 @@
+use blake2b_simd::Params;
+use sha3::Digest;
+
+/// Which hashers are fed with the data passing through a source or a sink.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct HashSelection {
+  pub blake2b: bool,
+  pub sha3: bool,
+  pub crc32: bool,
+}
+
+impl HashSelection {
+  pub fn any(&self) -> bool {
+    self.blake2b || self.sha3 || self.crc32
+  }
+}
+
+/// Running hasher state shared by `DataSource` and `DataSink`.
+#[derive(Debug, Clone)]
+pub struct Hashers {
+  pub selection: HashSelection,
+  pub blake2b: blake2b_simd::State,
+  pub sha_3_512: sha3::Sha3_512,
+  pub crc32: crc32fast::Hasher,
+}
+
+impl Hashers {
+  pub fn new(selection: HashSelection) -> Self {
+    Hashers {
+      selection,
+      blake2b: Params::new().hash_length(64).to_state(),
+      sha_3_512: sha3::Sha3_512::new(),
+      crc32: crc32fast::Hasher::new(),
+    }
+  }
+
+  pub fn update(&mut self, data: &[u8]) {
+    if self.selection.blake2b {
+      self.blake2b.update(data);
+    }
+    if self.selection.sha3 {
+      self.sha_3_512.update(data);
+    }
+    if self.selection.crc32 {
+      self.crc32.update(data);
+    }
+  }
+
+  /// Finalizes copies of the running states, so hashing can continue afterwards.
+  pub fn digests(&self) -> Digests {
+    Digests {
+      blake2b: self.selection.blake2b.then(|| self.blake2b.finalize().to_hex().to_string()),
+      sha3_512: self.selection.sha3.then(|| format!("{:x}", self.sha_3_512.clone().finalize())),
+      crc32: self.selection.crc32.then(|| format!("{:08x}", self.crc32.clone().finalize())),
+    }
+  }
+}
+
+/// Finalized digests in lowercase hex, `None` for hashers that were not enabled.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct Digests {
+  pub blake2b: Option<String>,
+  pub sha3_512: Option<String>,
+  pub crc32: Option<String>,
+}
+
+impl Digests {
+  pub fn is_empty(&self) -> bool {
+    self.blake2b.is_none() && self.sha3_512.is_none() && self.crc32.is_none()
+  }
+
+  /// (algorithm name, digest) pairs in a stable order.
+  pub fn entries(&self) -> Vec<(&'static str, Option<&str>)> {
+    vec![
+      ("blake2b", self.blake2b.as_deref()),
+      ("sha3-512", self.sha3_512.as_deref()),
+      ("crc32", self.crc32.as_deref()),
+    ]
+  }
+
+  /// Names of the algorithms whose digests differ between `self` and `other`.
+  pub fn mismatches(&self, other: &Digests) -> Vec<&'static str> {
+    self.entries()
+      .into_iter()
+      .zip(other.entries())
+      .filter(|((_, a), (_, b))| a != b)
+      .map(|((name, _), _)| name)
+      .collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_digests_known_values() {
+    let mut hashers = Hashers::new(HashSelection { blake2b: true, sha3: true, crc32: true });
+    hashers.update(b"abc");
+    let digests = hashers.digests();
+    assert_eq!(digests.crc32.as_deref(), Some("352441c2"));
+    assert_eq!(
+      digests.sha3_512.as_deref(),
+      Some("b751850b1a57168a5693cd924b6b096e08f621827444f70d884f5d0240d2712e10e116e9192af3c91a7ec57647e3934057340b4cf408d5a56592f8274eec53f0")
+    );
+    assert_eq!(
+      digests.blake2b.as_deref(),
+      Some("ba80a53f981c4d0d6a2797b69f12f6e94c212f14685ac4b74b12bb6fdbffa2d17d87c5392aab792dc252d5de4533cc9518d38aa8dbf1925ab92386edd4009923")
+    );
+  }
+
+  #[test]
+  fn test_digests_only_selected() {
+    let mut hashers = Hashers::new(HashSelection { blake2b: false, sha3: false, crc32: true });
+    hashers.update(b"abc");
+    let digests = hashers.digests();
+    assert!(digests.blake2b.is_none());
+    assert!(digests.sha3_512.is_none());
+    assert!(digests.crc32.is_some());
+  }
+
+  #[test]
+  fn test_digests_mismatches() {
+    let mut a = Hashers::new(HashSelection { blake2b: true, sha3: false, crc32: true });
+    let mut b = a.clone();
+    a.update(b"abc");
+    b.update(b"abd");
+    assert_eq!(a.digests().mismatches(&b.digests()), vec!["blake2b", "crc32"]);
+    assert!(a.digests().mismatches(&a.digests()).is_empty());
+  }
+}
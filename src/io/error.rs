@@ -1,5 +1,52 @@
 use std::fmt::Display;
 
+/// Why a run failed, each kind has a stable process exit code that callers may rely on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    Other,
+    Usage,
+    SourceOpen,
+    SinkOpen,
+    Read,
+    Write,
+    VerificationMismatch,
+    Interrupted,
+    PartialCopy,
+    SourceChanged,
+}
+
+impl ErrorKind {
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            ErrorKind::Other => 1,
+            ErrorKind::Usage => 2,
+            ErrorKind::SourceOpen => 3,
+            ErrorKind::SinkOpen => 4,
+            ErrorKind::Read => 5,
+            ErrorKind::Write => 6,
+            ErrorKind::VerificationMismatch => 7,
+            ErrorKind::Interrupted => 8,
+            ErrorKind::PartialCopy => 9,
+            ErrorKind::SourceChanged => 10,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ErrorKind::Other => "other",
+            ErrorKind::Usage => "usage",
+            ErrorKind::SourceOpen => "source_open",
+            ErrorKind::SinkOpen => "sink_open",
+            ErrorKind::Read => "read",
+            ErrorKind::Write => "write",
+            ErrorKind::VerificationMismatch => "verification_mismatch",
+            ErrorKind::Interrupted => "interrupted",
+            ErrorKind::PartialCopy => "partial_copy",
+            ErrorKind::SourceChanged => "source_changed",
+        }
+    }
+}
+
 
 
 impl std::error::Error for IoError {}
@@ -11,32 +58,184 @@ pub enum IoError {
     InputFileOpenError(String),
     FileMetadataAcquireError(String),
     ChannelEror(String),
+    ManifestWriteError(String),
+    ManifestReadError(String),
+    ReadError(String),
+    VerifyError(String),
+    OutputFileOpenError(String),
+    OutputFileNoWritePermission(String),
+    InputFileSeekError(String),
+    OutputFileSeekError(String),
+    WriteError(String),
+    OutputNoSpace(String),
+    OutputDeviceError(String),
+    ShortWrite(String),
+    MapfileError(String),
+    JournalError(String),
+    SourceChanged(String),
+}
+
+impl IoError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            IoError::InputFileDoesNotExist(_)
+            | IoError::InputFileNoReadPermission(_)
+            | IoError::InputFileOpenError(_)
+            | IoError::InputFileSeekError(_) => ErrorKind::SourceOpen,
+            IoError::OutputFileOpenError(_)
+            | IoError::OutputFileNoWritePermission(_)
+            | IoError::OutputFileSeekError(_) => ErrorKind::SinkOpen,
+            IoError::ReadError(_) | IoError::VerifyError(_) => ErrorKind::Read,
+            IoError::SourceChanged(_) => ErrorKind::SourceChanged,
+            IoError::WriteError(_)
+            | IoError::OutputNoSpace(_)
+            | IoError::OutputDeviceError(_)
+            | IoError::ShortWrite(_) => ErrorKind::Write,
+            IoError::FileMetadataAcquireError(_)
+            | IoError::ChannelEror(_)
+            | IoError::ManifestWriteError(_)
+            | IoError::ManifestReadError(_)
+            | IoError::MapfileError(_)
+            | IoError::JournalError(_) => ErrorKind::Other,
+        }
+    }
+
+    /// This error as a failure that keeps the errno of the OS error `cause`.
+    pub fn caused_by(self, cause: &std::io::Error) -> Failure {
+        Failure { error: self, offset: None, errno: cause.raw_os_error() }
+    }
+
+    /// Classifies a failed write `offset` bytes into the output.
+    pub fn from_write(error: &std::io::Error, offset: usize) -> Self {
+        let message = format!("at offset {}: {}", offset, error);
+        match error.raw_os_error() {
+            Some(libc::ENOSPC) => IoError::OutputNoSpace(message),
+            Some(libc::EIO) => IoError::OutputDeviceError(message),
+            _ if error.kind() == std::io::ErrorKind::WriteZero => IoError::ShortWrite(message),
+            _ => IoError::WriteError(message),
+        }
+    }
 }
 
 impl Display for IoError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             IoError::InputFileDoesNotExist(e) => write!(f, "Input file does not exist: {}", e),
-            IoError::InputFileNoReadPermission(e) => write!(f, "Input file is read-only: {}", e),
+            IoError::InputFileNoReadPermission(e) => write!(f, "Input file is not readable: {}", e),
             IoError::InputFileOpenError(e) => write!(f, "Input file open error: {}", e),
             IoError::FileMetadataAcquireError(e) => write!(f, "File metadata acquire error: {}", e),
             IoError::ChannelEror(e) => write!(f, "Channel error: {}", e),
+            IoError::ManifestWriteError(e) => write!(f, "Manifest write error: {}", e),
+            IoError::ManifestReadError(e) => write!(f, "Manifest read error: {}", e),
+            IoError::ReadError(e) => write!(f, "Read error: {}", e),
+            IoError::VerifyError(e) => write!(f, "Read-back verification error: {}", e),
+            IoError::OutputFileOpenError(e) => write!(f, "Output file open error: {}", e),
+            IoError::OutputFileNoWritePermission(e) => write!(f, "Output file is read-only: {}", e),
+            IoError::InputFileSeekError(e) => write!(f, "Input file seek error: {}", e),
+            IoError::OutputFileSeekError(e) => write!(f, "Output file seek error: {}", e),
+            IoError::WriteError(e) => write!(f, "Write error: {}", e),
+            IoError::OutputNoSpace(e) => write!(f, "No space left on output device: {}", e),
+            IoError::OutputDeviceError(e) => write!(f, "Output device I/O error: {}", e),
+            IoError::ShortWrite(e) => write!(f, "Short write: {}", e),
+            IoError::MapfileError(e) => write!(f, "Mapfile error: {}", e),
+            IoError::JournalError(e) => write!(f, "Journal error: {}", e),
+            IoError::SourceChanged(e) => write!(f, "Source changed while reading: {}", e),
         }
     }
 }
 
 
 
+/// An error that stopped the copy, with the output or input offset and errno when they are known.
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub struct Failure {
+    pub error: IoError,
+    pub offset: Option<u64>,
+    pub errno: Option<i32>,
+}
+
+impl Failure {
+    pub fn read(error: &std::io::Error, offset: usize) -> Self {
+        Failure {
+            error: IoError::ReadError(format!("at offset {}: {}", offset, error)),
+            offset: Some(offset as u64),
+            errno: error.raw_os_error(),
+        }
+    }
+
+    pub fn write(error: &std::io::Error, offset: usize) -> Self {
+        Failure {
+            error: IoError::from_write(error, offset),
+            offset: Some(offset as u64),
+            errno: error.raw_os_error(),
+        }
+    }
+}
+
+impl From<IoError> for Failure {
+    fn from(error: IoError) -> Self {
+        Failure { error, offset: None, errno: None }
+    }
+}
+
+impl Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub enum DdError {
     IoError(IoError),
+    CopyFailed(Failure),
+    DigestMismatch(String),
+    VerificationFailed(String),
+    Interrupted(String),
+    PartialCopy(String),
+    Usage(String),
     OtherError(String),
 }
 
+impl DdError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            DdError::IoError(e) => e.kind(),
+            DdError::CopyFailed(failure) => failure.error.kind(),
+            DdError::DigestMismatch(_) | DdError::VerificationFailed(_) => ErrorKind::VerificationMismatch,
+            DdError::Interrupted(_) => ErrorKind::Interrupted,
+            DdError::PartialCopy(_) => ErrorKind::PartialCopy,
+            DdError::Usage(_) => ErrorKind::Usage,
+            DdError::OtherError(_) => ErrorKind::Other,
+        }
+    }
+
+    /// One line JSON object with the error kind, exit code, message, offset and errno.
+    pub fn to_json(&self) -> String {
+        let (offset, errno) = match self {
+            DdError::CopyFailed(failure) => (failure.offset, failure.errno),
+            _ => (None, None),
+        };
+        serde_json::json!({
+            "kind": self.kind().name(),
+            "exit_code": self.kind().exit_code(),
+            "message": self.to_string(),
+            "offset": offset,
+            "errno": errno,
+        })
+        .to_string()
+    }
+}
+
 impl Display for DdError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             DdError::IoError(e) => write!(f, "IO error: {}", e),
+            DdError::CopyFailed(e) => write!(f, "IO error: {}", e),
+            DdError::DigestMismatch(e) => write!(f, "Digest mismatch: {}", e),
+            DdError::VerificationFailed(e) => write!(f, "Verification failed: {}", e),
+            DdError::Interrupted(e) => write!(f, "Interrupted: {}", e),
+            DdError::PartialCopy(e) => write!(f, "Partial copy: {}", e),
+            DdError::Usage(e) => write!(f, "Usage error: {}", e),
             DdError::OtherError(e) => write!(f, "Other error: {}", e),
         }
     }
@@ -47,3 +246,49 @@ impl From<IoError> for DdError {
         DdError::IoError(e)
     }
 }
+
+/// Keeps the first line of clap's report, the usage and help hints below it are for people.
+impl From<clap::Error> for DdError {
+    fn from(e: clap::Error) -> Self {
+        let report = e.to_string();
+        let message = report.lines().next().unwrap_or_default();
+        DdError::Usage(message.trim_start_matches("error: ").to_string())
+    }
+}
+
+impl From<Failure> for DdError {
+    fn from(e: Failure) -> Self {
+        DdError::CopyFailed(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_errors_are_classified() {
+        let enospc = Failure::write(&std::io::Error::from_raw_os_error(libc::ENOSPC), 512);
+        assert!(matches!(enospc.error, IoError::OutputNoSpace(_)));
+        assert_eq!(enospc.errno, Some(libc::ENOSPC));
+        let eio = Failure::write(&std::io::Error::from_raw_os_error(libc::EIO), 0);
+        assert!(matches!(eio.error, IoError::OutputDeviceError(_)));
+        let short = Failure::write(&std::io::ErrorKind::WriteZero.into(), 0);
+        assert!(matches!(short.error, IoError::ShortWrite(_)));
+    }
+
+    #[test]
+    fn test_exit_codes_and_json() {
+        assert_eq!(DdError::from(IoError::InputFileDoesNotExist("x".into())).kind().exit_code(), 3);
+        assert_eq!(DdError::from(IoError::OutputFileOpenError("x".into())).kind().exit_code(), 4);
+        assert_eq!(DdError::DigestMismatch("blake2b".into()).kind().exit_code(), 7);
+        assert_eq!(DdError::from(Failure::from(IoError::SourceChanged("x".into()))).kind().exit_code(), 10);
+        let error = DdError::from(Failure::write(&std::io::Error::from_raw_os_error(libc::ENOSPC), 4096));
+        assert_eq!(error.kind().exit_code(), 6);
+        let json: serde_json::Value = serde_json::from_str(&error.to_json()).unwrap();
+        assert_eq!(json["kind"], "write");
+        assert_eq!(json["exit_code"], 6);
+        assert_eq!(json["offset"], 4096);
+        assert_eq!(json["errno"], libc::ENOSPC);
+    }
+}
@@ -0,0 +1,250 @@
+use std::fs::File;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::io::digest::{HashSelection, Hashers};
+use crate::io::error::IoError;
+use crate::io::sink::config::SinkConfig;
+use crate::io::sink::verify::SegmentChecksums;
+use crate::io::source::config::SourceConfig;
+
+/// How often the sink makes its output durable and records how far it got.
+pub const JOURNAL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Which file a journal was written for.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct Identity {
+  pub path: PathBuf,
+  pub inode: u64,
+  pub size: u64,
+}
+
+impl Identity {
+  pub fn of(path: &Path) -> Result<Self, IoError> {
+    let metadata = std::fs::metadata(path).map_err(|e| IoError::FileMetadataAcquireError(format!("{}: {}", path.display(), e)))?;
+    Ok(Identity { path: path.to_path_buf(), inode: metadata.ino(), size: metadata.len() })
+  }
+}
+
+/// State of a copy that can be resumed: what is copied where, and how much of it is durable.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct Journal {
+  pub job_id: uuid::Uuid,
+  pub source: Identity,
+  pub sink: Identity,
+  pub skip_bytes: u64,
+  pub seek_bytes: u64,
+  /// bytes after `seek_bytes` that were synced to the output
+  pub committed: u64,
+  /// CRC32 of the committed bytes, checked against the output when resuming
+  pub crc32: u32,
+  pub updated_at: hifitime::Epoch,
+}
+
+impl Journal {
+  /// A journal for a copy that has not written anything yet.
+  pub fn start(job_id: uuid::Uuid, source: &SourceConfig, sink: &SinkConfig) -> Result<Self, IoError> {
+    let (Some(input), Some(output)) = (&source.input_file, &sink.output_file) else {
+      return Err(IoError::JournalError("a journal needs named input and output files".to_string()));
+    };
+    Ok(Journal {
+      job_id,
+      source: Identity::of(input)?,
+      // filled in by the sink once the output is open
+      sink: Identity { path: output.clone(), inode: 0, size: 0 },
+      skip_bytes: (source.skip * source.block_size) as u64,
+      seek_bytes: (sink.seek * sink.block_size) as u64,
+      committed: 0,
+      crc32: 0,
+      updated_at: hifitime::Epoch::now().unwrap(),
+    })
+  }
+
+  pub fn load(path: &Path) -> Result<Self, IoError> {
+    let content = std::fs::read_to_string(path).map_err(|e| IoError::JournalError(format!("{}: {}", path.display(), e)))?;
+    serde_json::from_str(&content).map_err(|e| IoError::JournalError(format!("{}: {}", path.display(), e)))
+  }
+
+  /// Writes the journal under a temporary name and renames it, so a crash leaves the previous one intact.
+  pub fn save(&self, path: &Path) -> Result<(), IoError> {
+    let mut temporary = path.to_path_buf().into_os_string();
+    temporary.push(".tmp");
+    let content = serde_json::to_string_pretty(self).map_err(|e| IoError::JournalError(e.to_string()))?;
+    std::fs::write(&temporary, content).map_err(|e| IoError::JournalError(e.to_string()))?;
+    std::fs::rename(&temporary, path).map_err(|e| IoError::JournalError(e.to_string()))
+  }
+
+  /// Fails unless `source` and `sink` are the files, with the same offsets, this journal was written for.
+  pub fn validate(&self, source: &Identity, sink: &Identity, skip_bytes: u64, seek_bytes: u64) -> Result<(), IoError> {
+    if *source != self.source {
+      return Err(IoError::JournalError(format!("input changed since the journal was written: {:?} is now {:?}", self.source, source)));
+    }
+    if sink.path != self.sink.path || sink.inode != self.sink.inode {
+      return Err(IoError::JournalError(format!("{} is not the output the journal was written for", sink.path.display())));
+    }
+    if sink.size < self.seek_bytes + self.committed {
+      return Err(IoError::JournalError(format!("{} is shorter than the committed {} bytes", sink.path.display(), self.committed)));
+    }
+    if skip_bytes != self.skip_bytes || seek_bytes != self.seek_bytes {
+      return Err(IoError::JournalError("skip and seek must be the same as in the interrupted run".to_string()));
+    }
+    Ok(())
+  }
+}
+
+/// Keeps the journal of a running copy up to date, driven by the sink.
+#[derive(Debug)]
+pub struct JournalWriter {
+  pub path: PathBuf,
+  pub record: Journal,
+  crc32: crc32fast::Hasher,
+  // commits only happen at multiples of this, so the source can resume at a block boundary
+  granularity: u64,
+  last_commit: Instant,
+}
+
+impl JournalWriter {
+  pub fn new(path: PathBuf, record: Journal, granularity: usize) -> Self {
+    let crc32 = crc32fast::Hasher::new_with_initial_len(record.crc32, record.committed);
+    JournalWriter { path, record, crc32, granularity: granularity as u64, last_commit: Instant::now() }
+  }
+
+  pub fn update(&mut self, data: &[u8]) {
+    self.crc32.update(data);
+  }
+
+  /// True when `written` bytes may be committed now.
+  pub fn due(&self, written: u64) -> bool {
+    self.last_commit.elapsed() >= JOURNAL_INTERVAL && written.is_multiple_of(self.granularity)
+  }
+
+  /// Records `written` bytes as durable, the caller must have synced them.
+  pub fn commit(&mut self, written: u64) -> Result<(), IoError> {
+    self.record.committed = written;
+    self.record.crc32 = self.crc32.clone().finalize();
+    self.record.updated_at = hifitime::Epoch::now().unwrap();
+    self.last_commit = Instant::now();
+    self.record.save(&self.path)
+  }
+
+  /// The copy finished, nothing is left to resume.
+  pub fn remove(&self) -> Result<(), IoError> {
+    std::fs::remove_file(&self.path).map_err(|e| IoError::JournalError(e.to_string()))
+  }
+}
+
+/// Hashes of the prefix an interrupted run already wrote, so the final digests cover the whole copy.
+#[derive(Debug, Clone)]
+pub struct Prefix {
+  pub hashers: Hashers,
+  pub segments: SegmentChecksums,
+  pub crc32: u32,
+}
+
+/// Reads `length` bytes at `offset` of the output back.
+pub fn rehash_prefix(path: &Path, offset: u64, length: u64, selection: HashSelection) -> Result<Prefix, IoError> {
+  let file = File::open(path).map_err(|e| IoError::OutputFileOpenError(e.to_string()))?;
+  let mut buffer = vec![0u8; 1 << 20];
+  let mut hashers = Hashers::new(selection);
+  let mut segments = SegmentChecksums::new();
+  let mut crc32 = crc32fast::Hasher::new();
+  let mut done = 0;
+  while done < length {
+    let take = (length - done).min(buffer.len() as u64) as usize;
+    let read = file.read_at(&mut buffer[..take], offset + done).map_err(|e| IoError::JournalError(e.to_string()))?;
+    if read == 0 {
+      return Err(IoError::JournalError(format!("{} ends before the committed offset", path.display())));
+    }
+    hashers.update(&buffer[..read]);
+    segments.update(&buffer[..read]);
+    crc32.update(&buffer[..read]);
+    done += read as u64;
+  }
+  Ok(Prefix { hashers, segments, crc32: crc32.finalize() })
+}
+
+/// Checks `journal` against the files `source` and `sink` name and sets both up to continue
+/// after the committed bytes.
+pub async fn resume(journal: &Journal, source: &mut SourceConfig, sink: &mut SinkConfig) -> Result<(), IoError> {
+  let (Some(input), Some(output)) = (source.input_file.clone(), sink.output_file.clone()) else {
+    return Err(IoError::JournalError("resuming needs named input and output files".to_string()));
+  };
+  let skip_bytes = (source.skip * source.block_size) as u64;
+  let seek_bytes = (sink.seek * sink.block_size) as u64;
+  journal.validate(&Identity::of(&input)?, &Identity::of(&output)?, skip_bytes, seek_bytes)?;
+
+  let selection = sink.hash_selection();
+  let (committed, expected) = (journal.committed, journal.crc32);
+  let prefix = tokio::task::spawn_blocking(move || rehash_prefix(&output, seek_bytes, committed, selection))
+    .await
+    .map_err(|e| IoError::JournalError(e.to_string()))??;
+  if prefix.crc32 != expected {
+    return Err(IoError::JournalError(format!("the first {} bytes of the output no longer match the journal", committed)));
+  }
+
+  source.resume_offset = committed as usize;
+  source.initial_hashers = Some(prefix.hashers.clone());
+  sink.resume_offset = committed as usize;
+  sink.initial_hashers = Some(prefix.hashers);
+  sink.initial_segments = Some(prefix.segments);
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn journal_for(dir: &Path) -> Journal {
+    let input = dir.join("input");
+    let output = dir.join("output");
+    std::fs::write(&input, vec![3u8; 8192]).unwrap();
+    std::fs::write(&output, vec![3u8; 4096]).unwrap();
+    Journal {
+      job_id: uuid::Uuid::now_v7(),
+      source: Identity::of(&input).unwrap(),
+      sink: Identity::of(&output).unwrap(),
+      skip_bytes: 0,
+      seek_bytes: 0,
+      committed: 4096,
+      crc32: crc32fast::hash(&[3u8; 4096]),
+      updated_at: hifitime::Epoch::now().unwrap(),
+    }
+  }
+
+  #[test]
+  fn test_journal_round_trip() {
+    let dir = tempfile::tempdir().unwrap();
+    let journal = journal_for(dir.path());
+    let path = dir.path().join("journal");
+    journal.save(&path).unwrap();
+    assert_eq!(Journal::load(&path).unwrap(), journal);
+  }
+
+  #[test]
+  fn test_journal_detects_changed_input() {
+    let dir = tempfile::tempdir().unwrap();
+    let journal = journal_for(dir.path());
+    std::fs::write(dir.path().join("input"), vec![3u8; 100]).unwrap();
+    let source = Identity::of(&dir.path().join("input")).unwrap();
+    let sink = Identity::of(&dir.path().join("output")).unwrap();
+    assert!(matches!(journal.validate(&source, &sink, 0, 0), Err(IoError::JournalError(_))));
+  }
+
+  #[tokio::test]
+  async fn test_resume_sets_offsets_and_checks_prefix() {
+    let dir = tempfile::tempdir().unwrap();
+    let journal = journal_for(dir.path());
+    let mut source = SourceConfig { input_file: Some(dir.path().join("input")), enable_blake2b: true, ..SourceConfig::default() };
+    let mut sink = SinkConfig { output_file: Some(dir.path().join("output")), enable_blake2b: true, ..SinkConfig::default() };
+    resume(&journal, &mut source, &mut sink).await.unwrap();
+    assert_eq!(source.resume_offset, 4096);
+    assert_eq!(sink.resume_offset, 4096);
+    assert!(sink.initial_hashers.is_some());
+
+    std::fs::write(dir.path().join("output"), vec![4u8; 4096]).unwrap();
+    let mut source = SourceConfig { input_file: Some(dir.path().join("input")), ..SourceConfig::default() };
+    let mut sink = SinkConfig { output_file: Some(dir.path().join("output")), ..SinkConfig::default() };
+    assert!(matches!(resume(&journal, &mut source, &mut sink).await, Err(IoError::JournalError(_))));
+  }
+}
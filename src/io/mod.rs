@@ -1,3 +1,15 @@
 pub mod source;
 pub mod sink;
 pub mod error;
+pub mod digest;
+pub mod aligned;
+pub mod buffers;
+pub mod chunk;
+pub mod device;
+pub mod journal;
+pub mod reblock;
+pub mod rescue;
+pub mod throttle;
+#[cfg(feature = "uring")]
+pub mod uring;
+pub mod zerocopy;
@@ -0,0 +1,91 @@
+use bytes::BytesMut;
+
+/// Joins or splits incoming buffers so that every block it hands out is exactly
+/// `block_size` bytes long, except the tail returned by `finish`.
+#[derive(Debug)]
+pub struct Reblocker {
+  block_size: usize,
+  pending: BytesMut,
+}
+
+impl Reblocker {
+  pub fn new(block_size: usize) -> Self {
+    Reblocker {
+      block_size,
+      pending: BytesMut::new(),
+    }
+  }
+
+  /// Feeds `data` in and returns the complete blocks that became available.
+  pub fn push(&mut self, mut data: BytesMut) -> Vec<BytesMut> {
+    let mut blocks = Vec::new();
+    if !self.pending.is_empty() {
+      let take = (self.block_size - self.pending.len()).min(data.len());
+      self.pending.extend_from_slice(&data.split_to(take));
+      if self.pending.len() < self.block_size {
+        return blocks;
+      }
+      blocks.push(std::mem::take(&mut self.pending));
+    }
+    // whole blocks are split off without copying
+    while data.len() >= self.block_size {
+      blocks.push(data.split_to(self.block_size));
+    }
+    if !data.is_empty() {
+      self.pending = data;
+    }
+    blocks
+  }
+
+  /// Returns the short last block, if there is one.
+  pub fn finish(&mut self) -> Option<BytesMut> {
+    match self.pending.is_empty() {
+      true => None,
+      false => Some(std::mem::take(&mut self.pending)),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn sizes(blocks: &[BytesMut]) -> Vec<usize> {
+    blocks.iter().map(|b| b.len()).collect()
+  }
+
+  #[test]
+  fn test_reblock_joins_small_buffers() {
+    let mut reblocker = Reblocker::new(4);
+    assert!(reblocker.push(BytesMut::from(&b"ab"[..])).is_empty());
+    let blocks = reblocker.push(BytesMut::from(&b"cdefg"[..]));
+    assert_eq!(blocks, vec![BytesMut::from(&b"abcd"[..])]);
+    assert_eq!(reblocker.finish(), Some(BytesMut::from(&b"efg"[..])));
+    assert_eq!(reblocker.finish(), None);
+  }
+
+  #[test]
+  fn test_reblock_splits_large_buffers() {
+    let mut reblocker = Reblocker::new(3);
+    let blocks = reblocker.push(BytesMut::from(&b"0123456"[..]));
+    assert_eq!(sizes(&blocks), vec![3, 3]);
+    let blocks = reblocker.push(BytesMut::from(&b"78"[..]));
+    assert_eq!(blocks, vec![BytesMut::from(&b"678"[..])]);
+    assert_eq!(reblocker.finish(), None);
+  }
+
+  #[test]
+  fn test_reblock_preserves_data() {
+    let data: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+    let mut reblocker = Reblocker::new(64);
+    let mut out = Vec::new();
+    for piece in data.chunks(37) {
+      for block in reblocker.push(BytesMut::from(piece)) {
+        assert_eq!(block.len(), 64);
+        out.extend_from_slice(&block);
+      }
+    }
+    out.extend_from_slice(&reblocker.finish().unwrap());
+    assert_eq!(out, data);
+  }
+}
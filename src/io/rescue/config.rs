@@ -0,0 +1,55 @@
+use std::path::PathBuf;
+
+#[derive(derivative::Derivative)]
+#[derivative(Default)]
+pub struct RescueConfig {
+  pub input_file: PathBuf,
+  pub output_file: PathBuf,
+  pub mapfile: PathBuf,
+  // size of the reads of the first, copying pass
+  #[derivative(Default(value = "65536"))]
+  pub block_size: usize,
+  // size of the reads used to trim and retry bad areas
+  #[derivative(Default(value = "512"))]
+  pub sector_size: usize,
+  #[derivative(Default(value = "1"))]
+  pub retries: usize,
+}
+
+impl RescueConfig {
+  pub fn new() -> Self {
+    RescueConfig::default()
+  }
+}
+
+impl From<&crate::config::Args> for RescueConfig {
+  fn from(args: &crate::config::Args) -> Self {
+    let default = RescueConfig::default();
+    RescueConfig {
+      input_file: PathBuf::from(args.input_file.clone().unwrap_or_default()),
+      output_file: PathBuf::from(args.output_file.clone().unwrap_or_default()),
+      mapfile: PathBuf::from(args.rescue.clone().unwrap_or_default()),
+      // dd's 512 byte default is the sector size, the copying pass would be as slow as the retries
+      block_size: args.block_size().or(args.ibs).unwrap_or(default.block_size),
+      sector_size: args.rescue_sector,
+      retries: args.rescue_retries,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use clap::Parser;
+
+  #[test]
+  fn test_copying_pass_reads_more_than_a_sector() {
+    let config = RescueConfig::from(&crate::config::Args::parse_from(["ruplica", "--if", "in", "--of", "out", "--rescue", "map"]));
+    assert_eq!((config.block_size, config.sector_size), (65536, 512));
+    assert_ne!(config.block_size, config.sector_size);
+    let config = RescueConfig::from(&crate::config::Args::parse_from(["ruplica", "--if", "in", "--of", "out", "--rescue", "map", "--ibs", "4K"]));
+    assert_eq!(config.block_size, 4096);
+    let config = RescueConfig::from(&crate::config::Args::parse_from(["ruplica", "--if", "in", "--of", "out", "--rescue", "map", "--bs", "1M", "--ibs", "4K"]));
+    assert_eq!(config.block_size, 1 << 20);
+  }
+}
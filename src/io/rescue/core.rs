@@ -0,0 +1,339 @@
+use std::fs::File;
+use std::io::{Seek, SeekFrom};
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::sync::Mutex;
+
+use crate::environment::statistics::{DdContext, ReadStatistics, WriteStatistics};
+use crate::io::error::{Failure, IoError};
+use crate::io::rescue::config::RescueConfig;
+use crate::io::rescue::mapfile::{BlockStatus, Mapfile, Phase};
+
+/// How often the mapfile is rewritten while a pass is running.
+const SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+  Forward,
+  Reverse,
+}
+
+/// Copies what can still be read from a failing input, the way GNU ddrescue does.
+///
+/// A forward copying pass reads `block_size` chunks and skips every chunk that fails. A reverse
+/// trimming pass then reads the failed chunks sector by sector, and retry passes re-read the bad
+/// sectors that are left, alternating direction. Everything is written at the same offset of the
+/// output and the state of every byte is kept in the mapfile, so an interrupted rescue resumes.
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+pub struct Rescuer {
+  #[derivative(Debug="ignore")]
+  input: File,
+  #[derivative(Debug="ignore")]
+  output: File,
+  pub map: Mapfile,
+  mapfile: PathBuf,
+  block_size: usize,
+  sector_size: usize,
+  retries: usize,
+  #[derivative(Debug="ignore")]
+  read_statistics: Arc<Mutex<ReadStatistics>>,
+  #[derivative(Debug="ignore")]
+  write_statistics: Arc<Mutex<WriteStatistics>>,
+  #[derivative(Debug="ignore")]
+  stop: Arc<AtomicBool>,
+  last_save: Instant,
+}
+
+impl Rescuer {
+  /// Opens input and output and loads the mapfile, or starts a new one when it does not exist yet.
+  pub fn open(config: &RescueConfig, dd_context: &DdContext, stop: Arc<AtomicBool>) -> Result<Self, Failure> {
+    let mut input = File::open(&config.input_file).map_err(|e| match e.kind() {
+      std::io::ErrorKind::NotFound => IoError::InputFileDoesNotExist(config.input_file.display().to_string()),
+      std::io::ErrorKind::PermissionDenied => IoError::InputFileNoReadPermission(config.input_file.display().to_string()),
+      _ => IoError::InputFileOpenError(e.to_string()),
+    }.caused_by(&e))?;
+    // also works for block devices, their metadata reports a length of 0
+    let size = input.seek(SeekFrom::End(0)).map_err(|e| IoError::InputFileSeekError(e.to_string()).caused_by(&e))?;
+    // never truncated, an earlier run may already have rescued parts of it
+    let output = std::fs::OpenOptions::new()
+      .write(true)
+      .create(true)
+      .truncate(false)
+      .open(&config.output_file)
+      .map_err(|e| IoError::OutputFileOpenError(format!("{}: {}", config.output_file.display(), e)).caused_by(&e))?;
+
+    let map = match std::fs::read_to_string(&config.mapfile) {
+      Ok(content) => {
+        let map = Mapfile::parse(&content)?;
+        if map.size() != size {
+          return Err(IoError::MapfileError(format!(
+            "{} covers {} bytes but the input has {}",
+            config.mapfile.display(),
+            map.size(),
+            size
+          )).into());
+        }
+        tracing::info!("Resuming rescue from {}", config.mapfile.display());
+        map
+      },
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => Mapfile::new(size),
+      Err(e) => return Err(IoError::MapfileError(e.to_string()).caused_by(&e)),
+    };
+
+    Ok(Rescuer {
+      input,
+      output,
+      map,
+      mapfile: config.mapfile.clone(),
+      block_size: config.block_size,
+      sector_size: config.sector_size,
+      retries: config.retries,
+      read_statistics: dd_context.read_statistics.clone(),
+      write_statistics: dd_context.write_statistics.clone(),
+      stop,
+      last_save: Instant::now(),
+    })
+  }
+
+  /// Writes the mapfile next to its final name and renames it, so a crash never leaves half of one.
+  pub fn save(&mut self) -> Result<(), IoError> {
+    let mut temporary = self.mapfile.clone().into_os_string();
+    temporary.push(".tmp");
+    std::fs::write(&temporary, self.map.render()).map_err(|e| IoError::MapfileError(e.to_string()))?;
+    std::fs::rename(&temporary, &self.mapfile).map_err(|e| IoError::MapfileError(e.to_string()))?;
+    self.last_save = Instant::now();
+    Ok(())
+  }
+
+  fn stopped(&self) -> bool {
+    self.stop.load(Ordering::Relaxed)
+  }
+
+  /// Reads `size` bytes at `position` and writes them to the same offset of the output.
+  /// Returns false when the input could not be read, a failing write ends the rescue.
+  fn transfer(&mut self, position: u64, size: usize, buffer: &mut [u8]) -> Result<bool, Failure> {
+    let buffer = &mut buffer[..size];
+    let mut filled = 0;
+    while filled < size {
+      match self.input.read_at(&mut buffer[filled..], position + filled as u64) {
+        // the map was made for a longer input, what is missing cannot be rescued
+        Ok(0) => break,
+        Ok(read) => filled += read,
+        Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+        Err(e) => {
+          tracing::debug!("Read error at offset {}: {}", position + filled as u64, e);
+          break;
+        },
+      }
+    }
+    if filled < size {
+      self.read_statistics.blocking_lock().add_error();
+      return Ok(false);
+    }
+    self.read_statistics.blocking_lock().add_read(size as u64, size == self.block_size);
+    self.output.write_all_at(buffer, position).map_err(|e| Failure::write(&e, position as usize))?;
+    self.write_statistics.blocking_lock().add_write(size as u64, size == self.block_size);
+    Ok(true)
+  }
+
+  /// Reads every area in `status` in `step` sized pieces, marking each piece `Finished` or `failed`.
+  fn pass(&mut self, status: &[BlockStatus], step: usize, direction: Direction, failed: BlockStatus) -> Result<(), Failure> {
+    let mut buffer = vec![0u8; step];
+    let mut pieces: Vec<(u64, usize)> = Vec::new();
+    for status in status {
+      for (position, size) in self.map.areas(*status) {
+        let mut offset = 0;
+        while offset < size {
+          let length = (size - offset).min(step as u64);
+          pieces.push((position + offset, length as usize));
+          offset += length;
+        }
+      }
+    }
+    pieces.sort_unstable();
+    if direction == Direction::Reverse {
+      pieces.reverse();
+    }
+
+    for (position, size) in pieces {
+      if self.stopped() {
+        break;
+      }
+      let status = match self.transfer(position, size, &mut buffer)? {
+        true => BlockStatus::Finished,
+        false => failed,
+      };
+      self.map.set(position, size as u64, status);
+      self.map.current_position = position;
+      if self.last_save.elapsed() >= SAVE_INTERVAL {
+        self.save()?;
+      }
+    }
+    Ok(())
+  }
+
+  fn enter(&mut self, phase: Phase, pass: u32) -> Result<(), Failure> {
+    tracing::info!("Rescue phase {:?}, pass {}", phase, pass);
+    self.map.phase = phase;
+    self.map.pass = pass;
+    self.save()?;
+    Ok(())
+  }
+
+  /// Runs the remaining passes, stopping early when `stop` is set. The mapfile is saved in any case.
+  pub fn rescue(&mut self) -> Result<(), Failure> {
+    let outcome = self.passes();
+    let saved = self.save();
+    outcome?;
+    saved?;
+    Ok(())
+  }
+
+  fn passes(&mut self) -> Result<(), Failure> {
+    if self.map.phase == Phase::Copying {
+      self.pass(&[BlockStatus::NonTried], self.block_size, Direction::Forward, BlockStatus::NonTrimmed)?;
+      if self.stopped() {
+        return Ok(());
+      }
+      self.enter(Phase::Trimming, 1)?;
+    }
+    if self.map.phase == Phase::Trimming {
+      let failed = [BlockStatus::NonTried, BlockStatus::NonTrimmed, BlockStatus::NonScraped];
+      self.pass(&failed, self.sector_size, Direction::Reverse, BlockStatus::BadSector)?;
+      if self.stopped() {
+        return Ok(());
+      }
+      self.enter(Phase::Retrying, 1)?;
+    }
+    if self.map.phase == Phase::Retrying {
+      while (self.map.pass as usize) <= self.retries && self.map.bytes(BlockStatus::BadSector) > 0 {
+        let direction = match self.map.pass % 2 {
+          1 => Direction::Forward,
+          _ => Direction::Reverse,
+        };
+        self.pass(&[BlockStatus::BadSector], self.sector_size, direction, BlockStatus::BadSector)?;
+        if self.stopped() {
+          return Ok(());
+        }
+        self.enter(Phase::Retrying, self.map.pass + 1)?;
+      }
+      self.enter(Phase::Finished, self.map.pass)?;
+    }
+    Ok(())
+  }
+
+  /// Copies the rescued versus lost byte counts into the read statistics.
+  pub fn report(&self) {
+    let rescued = self.map.bytes(BlockStatus::Finished);
+    let mut statistics = self.read_statistics.blocking_lock();
+    statistics.rescued_bytes = rescued;
+    statistics.lost_bytes = self.map.size() - rescued;
+  }
+}
+
+/// Rescues `config.input_file` into `config.output_file`, returns the number of bytes that stay unread.
+#[tracing::instrument(skip(config, dd_context, stop), level="debug", ret, err)]
+pub async fn run(config: RescueConfig, dd_context: Arc<Mutex<DdContext>>, stop: Arc<AtomicBool>) -> Result<u64, Failure> {
+  let mut rescuer = Rescuer::open(&config, &*dd_context.lock().await, stop)?;
+  let task = dd_context.lock().await.new_task("Rescue").await;
+  {
+    let context = dd_context.lock().await;
+    context.read_statistics.lock().await.init();
+    context.read_statistics.lock().await.estimated_bytes = rescuer.map.size();
+    context.write_statistics.lock().await.init();
+  }
+  task.lock().await.change_state(crate::environment::statistics::TaskStatus::Running);
+
+  let (rescuer, outcome) = tokio::task::spawn_blocking(move || {
+    let outcome = rescuer.rescue();
+    rescuer.report();
+    (rescuer, outcome)
+  })
+  .await
+  .map_err(|e| Failure::from(IoError::ReadError(format!("rescue task failed: {}", e))))?;
+
+  match &outcome {
+    Ok(()) => task.lock().await.complete(0),
+    Err(e) => task.lock().await.fail(-2, e.to_string()),
+  }
+  outcome?;
+  Ok(rescuer.map.size() - rescuer.map.bytes(BlockStatus::Finished))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::io::rescue::mapfile::Block;
+
+  fn rescue(config: &RescueConfig) -> Rescuer {
+    let context = DdContext::new();
+    let mut rescuer = Rescuer::open(config, &context, Arc::new(AtomicBool::new(false))).unwrap();
+    rescuer.rescue().unwrap();
+    rescuer.report();
+    rescuer
+  }
+
+  #[test]
+  fn test_rescue_readable_input() {
+    let dir = tempfile::tempdir().unwrap();
+    let data: Vec<u8> = (0..10_000u32).map(|i| (i * 7) as u8).collect();
+    let config = RescueConfig {
+      input_file: dir.path().join("input"),
+      output_file: dir.path().join("output"),
+      mapfile: dir.path().join("map"),
+      block_size: 4096,
+      ..RescueConfig::default()
+    };
+    std::fs::write(&config.input_file, &data).unwrap();
+    let rescuer = rescue(&config);
+    assert_eq!(std::fs::read(&config.output_file).unwrap(), data);
+    assert_eq!(rescuer.map.phase, Phase::Finished);
+    assert_eq!(rescuer.map.blocks, vec![Block { position: 0, size: 10_000, status: BlockStatus::Finished }]);
+    assert_eq!(rescuer.read_statistics.blocking_lock().lost_bytes, 0);
+    assert_eq!(Mapfile::parse(&std::fs::read_to_string(&config.mapfile).unwrap()).unwrap(), rescuer.map);
+  }
+
+  #[test]
+  fn test_rescue_resumes_from_mapfile() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = RescueConfig {
+      input_file: dir.path().join("input"),
+      output_file: dir.path().join("output"),
+      mapfile: dir.path().join("map"),
+      block_size: 1024,
+      ..RescueConfig::default()
+    };
+    std::fs::write(&config.input_file, vec![1u8; 4096]).unwrap();
+    // a previous run rescued the first half
+    std::fs::write(&config.output_file, vec![9u8; 2048]).unwrap();
+    let mut map = Mapfile::new(4096);
+    map.set(0, 2048, BlockStatus::Finished);
+    std::fs::write(&config.mapfile, map.render()).unwrap();
+
+    let rescuer = rescue(&config);
+    let output = std::fs::read(&config.output_file).unwrap();
+    assert_eq!(&output[..2048], &[9u8; 2048][..]);
+    assert_eq!(&output[2048..], &[1u8; 2048][..]);
+    assert_eq!(rescuer.write_statistics.blocking_lock().total_bytes_written, 2048);
+  }
+
+  #[test]
+  fn test_rescue_rejects_mapfile_of_other_size() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = RescueConfig {
+      input_file: dir.path().join("input"),
+      output_file: dir.path().join("output"),
+      mapfile: dir.path().join("map"),
+      ..RescueConfig::default()
+    };
+    std::fs::write(&config.input_file, vec![1u8; 4096]).unwrap();
+    std::fs::write(&config.mapfile, Mapfile::new(8192).render()).unwrap();
+    let result = Rescuer::open(&config, &DdContext::new(), Arc::new(AtomicBool::new(false)));
+    assert!(matches!(result, Err(Failure { error: IoError::MapfileError(_), .. })));
+  }
+}
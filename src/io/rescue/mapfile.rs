@@ -0,0 +1,245 @@
+use crate::io::error::IoError;
+
+/// State of a block of the input, with the characters GNU ddrescue uses for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockStatus {
+  NonTried,
+  NonTrimmed,
+  NonScraped,
+  BadSector,
+  Finished,
+}
+
+impl BlockStatus {
+  pub fn symbol(&self) -> char {
+    match self {
+      BlockStatus::NonTried => '?',
+      BlockStatus::NonTrimmed => '*',
+      BlockStatus::NonScraped => '/',
+      BlockStatus::BadSector => '-',
+      BlockStatus::Finished => '+',
+    }
+  }
+
+  pub fn from_symbol(symbol: &str) -> Option<Self> {
+    match symbol {
+      "?" => Some(BlockStatus::NonTried),
+      "*" => Some(BlockStatus::NonTrimmed),
+      "/" => Some(BlockStatus::NonScraped),
+      "-" => Some(BlockStatus::BadSector),
+      "+" => Some(BlockStatus::Finished),
+      _ => None,
+    }
+  }
+}
+
+/// The pass a rescue is in, stored as the mapfile's current status.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+  Copying,
+  Trimming,
+  Retrying,
+  Finished,
+}
+
+impl Phase {
+  pub fn symbol(&self) -> char {
+    match self {
+      Phase::Copying => '?',
+      Phase::Trimming => '*',
+      Phase::Retrying => '-',
+      Phase::Finished => '+',
+    }
+  }
+
+  pub fn from_symbol(symbol: &str) -> Option<Self> {
+    match symbol {
+      "?" => Some(Phase::Copying),
+      // scraping is part of our trimming pass, filling and generating are not supported
+      "*" | "/" => Some(Phase::Trimming),
+      "-" => Some(Phase::Retrying),
+      "+" => Some(Phase::Finished),
+      _ => None,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Block {
+  pub position: u64,
+  pub size: u64,
+  pub status: BlockStatus,
+}
+
+impl Block {
+  pub fn end(&self) -> u64 {
+    self.position + self.size
+  }
+}
+
+/// A GNU ddrescue mapfile: the status of every byte of the input, kept as sorted adjacent blocks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mapfile {
+  pub current_position: u64,
+  pub phase: Phase,
+  pub pass: u32,
+  pub blocks: Vec<Block>,
+}
+
+fn parse_number(value: &str) -> Option<u64> {
+  match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+    Some(hex) => u64::from_str_radix(hex, 16).ok(),
+    None => value.parse().ok(),
+  }
+}
+
+impl Mapfile {
+  /// A map of `size` bytes nobody has tried to read yet.
+  pub fn new(size: u64) -> Self {
+    let blocks = match size {
+      0 => Vec::new(),
+      _ => vec![Block { position: 0, size, status: BlockStatus::NonTried }],
+    };
+    Mapfile { current_position: 0, phase: Phase::Copying, pass: 1, blocks }
+  }
+
+  pub fn size(&self) -> u64 {
+    self.blocks.last().map(|b| b.end()).unwrap_or(0)
+  }
+
+  pub fn parse(content: &str) -> Result<Self, IoError> {
+    let mut lines = content
+      .lines()
+      .enumerate()
+      .map(|(number, line)| (number + 1, line.trim()))
+      .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'));
+    let invalid = |number: usize| IoError::MapfileError(format!("line {}: improperly formatted", number));
+
+    let (number, status_line) = lines.next().ok_or_else(|| IoError::MapfileError("mapfile is empty".to_string()))?;
+    let fields: Vec<&str> = status_line.split_whitespace().collect();
+    if fields.len() < 2 {
+      return Err(invalid(number));
+    }
+    let current_position = parse_number(fields[0]).ok_or_else(|| invalid(number))?;
+    let phase = Phase::from_symbol(fields[1]).ok_or_else(|| invalid(number))?;
+    let pass = match fields.get(2) {
+      Some(pass) => pass.parse().map_err(|_| invalid(number))?,
+      None => 1,
+    };
+
+    let mut blocks: Vec<Block> = Vec::new();
+    for (number, line) in lines {
+      let fields: Vec<&str> = line.split_whitespace().collect();
+      let [position, size, status] = fields[..] else {
+        return Err(invalid(number));
+      };
+      let block = Block {
+        position: parse_number(position).ok_or_else(|| invalid(number))?,
+        size: parse_number(size).ok_or_else(|| invalid(number))?,
+        status: BlockStatus::from_symbol(status).ok_or_else(|| invalid(number))?,
+      };
+      let expected = blocks.last().map(|b| b.end()).unwrap_or(0);
+      if block.position != expected || block.size == 0 {
+        return Err(IoError::MapfileError(format!("line {}: blocks must be contiguous and not empty", number)));
+      }
+      blocks.push(block);
+    }
+    Ok(Mapfile { current_position, phase, pass, blocks })
+  }
+
+  pub fn render(&self) -> String {
+    let mut out = String::from("# Mapfile. Created by ruplica\n");
+    out += "# current_pos  current_status  current_pass\n";
+    out += &format!("0x{:08X}     {}               {}\n", self.current_position, self.phase.symbol(), self.pass);
+    out += "#      pos        size  status\n";
+    for block in self.blocks.iter() {
+      out += &format!("0x{:08X}  0x{:08X}  {}\n", block.position, block.size, block.status.symbol());
+    }
+    out
+  }
+
+  /// Marks `size` bytes at `position` with `status`, splitting and merging blocks as needed.
+  pub fn set(&mut self, position: u64, size: u64, status: BlockStatus) {
+    let end = (position + size).min(self.size());
+    if position >= end {
+      return;
+    }
+    let mut blocks: Vec<Block> = Vec::with_capacity(self.blocks.len() + 2);
+    let mut push = |block: Block| {
+      if block.size == 0 {
+        return;
+      }
+      match blocks.last_mut() {
+        Some(last) if last.status == block.status => last.size += block.size,
+        _ => blocks.push(block),
+      }
+    };
+    for block in self.blocks.iter() {
+      if block.end() <= position || block.position >= end {
+        push(*block);
+        continue;
+      }
+      push(Block { position: block.position, size: position.saturating_sub(block.position), status: block.status });
+      if block.position <= position {
+        push(Block { position, size: end - position, status });
+      }
+      push(Block { position: end, size: block.end().saturating_sub(end), status: block.status });
+    }
+    self.blocks = blocks;
+  }
+
+  /// Bytes currently in `status`.
+  pub fn bytes(&self, status: BlockStatus) -> u64 {
+    self.blocks.iter().filter(|b| b.status == status).map(|b| b.size).sum()
+  }
+
+  /// `(position, size)` of every block in `status`, in ascending order.
+  pub fn areas(&self, status: BlockStatus) -> Vec<(u64, u64)> {
+    self.blocks.iter().filter(|b| b.status == status).map(|b| (b.position, b.size)).collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_mapfile_set_splits_and_merges() {
+    let mut map = Mapfile::new(100);
+    map.set(0, 40, BlockStatus::Finished);
+    map.set(40, 10, BlockStatus::NonTrimmed);
+    map.set(50, 50, BlockStatus::Finished);
+    assert_eq!(map.areas(BlockStatus::NonTrimmed), vec![(40, 10)]);
+    map.set(44, 2, BlockStatus::BadSector);
+    assert_eq!(map.blocks.len(), 5);
+    map.set(40, 10, BlockStatus::Finished);
+    assert_eq!(map.blocks, vec![Block { position: 0, size: 100, status: BlockStatus::Finished }]);
+  }
+
+  #[test]
+  fn test_mapfile_round_trip() {
+    let mut map = Mapfile::new(0x10000);
+    map.set(0x1000, 0x200, BlockStatus::BadSector);
+    map.set(0x1200, 0x0E00, BlockStatus::NonTrimmed);
+    map.current_position = 0x2000;
+    map.phase = Phase::Trimming;
+    assert_eq!(Mapfile::parse(&map.render()).unwrap(), map);
+  }
+
+  #[test]
+  fn test_mapfile_parses_ddrescue_output() {
+    let content = "# Mapfile. Created by GNU ddrescue version 1.27\n\
+      # Command line: ddrescue /dev/sdb image map\n\
+      # current_pos  current_status  current_pass\n\
+      0x00120000     +               1\n\
+      #      pos        size  status\n\
+      0x00000000  0x00100000  +\n\
+      0x00100000  0x00000200  -\n\
+      0x00100200  0x0001FE00  +\n";
+    let map = Mapfile::parse(content).unwrap();
+    assert_eq!(map.phase, Phase::Finished);
+    assert_eq!(map.size(), 0x120000);
+    assert_eq!(map.bytes(BlockStatus::BadSector), 0x200);
+    assert!(Mapfile::parse("0x0 ?\n0x0 0x10 +\n0x20 0x10 +\n").is_err());
+  }
+}
@@ -1,10 +1,15 @@
 use std::path::PathBuf;
 
+use crate::config::{Conv, Flag};
+use crate::io::digest::{HashSelection, Hashers};
+use crate::io::journal::Journal;
+use crate::io::sink::verify::SegmentChecksums;
 
 #[derive(derivative::Derivative)]
 #[derivative(Default)]
 pub struct SinkConfig {
-  pub output_file: PathBuf,
+  // None writes to stdout
+  pub output_file: Option<PathBuf>,
   #[derivative(Default(value = "512"))]
   pub block_size: usize,
   #[derivative(Default(value = "false"))]
@@ -15,23 +20,88 @@ pub struct SinkConfig {
   pub enable_sha3: bool,
   #[derivative(Default(value = "false"))]
   pub enable_blake2b: bool,
+  #[derivative(Default(value = "false"))]
+  pub verify: bool,
+  #[derivative(Default(value = "true"))]
+  pub truncate: bool,
+  #[derivative(Default(value = "true"))]
+  pub create: bool,
+  #[derivative(Default(value = "false"))]
+  pub exclusive: bool,
+  #[derivative(Default(value = "false"))]
+  pub sparse: bool,
+  #[derivative(Default(value = "false"))]
+  pub fsync: bool,
+  #[derivative(Default(value = "false"))]
+  pub fdatasync: bool,
+  // oflag=direct, dsync, sync and nocache
+  #[derivative(Default(value = "false"))]
+  pub direct: bool,
+  #[derivative(Default(value = "false"))]
+  pub dsync: bool,
+  #[derivative(Default(value = "false"))]
+  pub sync: bool,
+  #[derivative(Default(value = "false"))]
+  pub nocache: bool,
+  // in blocks of block_size
+  pub seek: usize,
+  // write exactly block_size bytes at a time, whatever the source sends
+  #[derivative(Default(value = "false"))]
+  pub reblock: bool,
+  // where to keep the resume journal, and the record it starts from
+  pub journal: Option<(PathBuf, Journal)>,
+  // the journal only commits multiples of this, the input block size
+  #[derivative(Default(value = "512"))]
+  pub journal_granularity: usize,
+  // bytes after the seek offset an interrupted run already wrote
+  pub resume_offset: usize,
+  pub initial_hashers: Option<Hashers>,
+  pub initial_segments: Option<SegmentChecksums>,
+  // writer tasks for a seekable output, a stream is always written by one
+  #[derivative(Default(value = "1"))]
+  pub writers: usize,
 }
 
 impl SinkConfig {
   pub fn new() -> Self {
     SinkConfig::default()
   }
+
+  pub fn hash_selection(&self) -> HashSelection {
+    HashSelection {
+      blake2b: self.enable_blake2b,
+      sha3: self.enable_sha3,
+      crc32: self.enable_crc32,
+    }
+  }
 }
 
 impl From<&crate::config::Args> for SinkConfig {
   fn from(args: &crate::config::Args) -> Self {
+    let hashes = args.hash_selection();
     SinkConfig {
-      output_file: PathBuf::from(args.output_file.clone().unwrap_or("".to_string())),
-      block_size: args.bs,
-      enable_hash: false,
-      enable_crc32: false,
-      enable_sha3: false,
-      enable_blake2b: false,
+      output_file: args.output_file.clone().map(PathBuf::from),
+      block_size: args.obs(),
+      enable_hash: hashes.any(),
+      enable_crc32: hashes.crc32,
+      enable_sha3: hashes.sha3,
+      enable_blake2b: hashes.blake2b,
+      verify: args.verify,
+      truncate: !args.has_conv(Conv::Notrunc),
+      create: !args.has_conv(Conv::Nocreat),
+      exclusive: args.has_conv(Conv::Excl),
+      sparse: args.has_conv(Conv::Sparse),
+      fsync: args.has_conv(Conv::Fsync),
+      fdatasync: args.has_conv(Conv::Fdatasync),
+      direct: args.has_oflag(Flag::Direct),
+      dsync: args.has_oflag(Flag::Dsync),
+      sync: args.has_oflag(Flag::Sync),
+      nocache: args.has_oflag(Flag::Nocache),
+      seek: args.seek,
+      reblock: args.block_size().is_none(),
+      journal_granularity: args.ibs(),
+      writers: args.writers,
+      ..SinkConfig::default()
     }
   }
 }
\ No newline at end of file
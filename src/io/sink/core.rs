@@ -1,93 +1,394 @@
 #![allow(unused_imports)]
 
+use std::io::SeekFrom;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::FileExt;
 use std::sync::mpsc::{RecvError, TryRecvError};
 use std::thread::sleep;
 use std::{os::unix::fs::MetadataExt, path::PathBuf};
 use bytes::BytesMut;
-use tokio::io::{sink, AsyncWrite, AsyncWriteExt};
-use blake2b_simd::Params;
-use sha3::Digest;
+use tokio::io::{sink, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
 use tokio::sync::mpsc::{Sender, Receiver};
 use tokio::sync::{Mutex, Semaphore};
 use tokio::io::AsyncReadExt;
 use std::sync::Arc;
 use std::fs::Metadata;
 use crate::config;
-use crate::environment::statistics::{self, DdContext};
-use crate::io::error::IoError;
+use crate::environment::statistics::{self, DdContext, WriteStatistics};
+use crate::io::aligned::{AlignedBuffer, DIRECT_ALIGNMENT};
+use crate::io::buffers::BufferPool;
+use crate::io::chunk::{Chunk, Reorder};
+use crate::io::device::{self, SinkDevice, Stream};
+use crate::io::digest::Hashers;
+use crate::io::error::{Failure, IoError};
+use crate::io::journal::{Identity, JournalWriter};
+use crate::io::reblock::Reblocker;
 use crate::io::sink::config::SinkConfig;
+use crate::io::sink::pool::WriterPool;
+use crate::io::sink::verify::{self, SegmentChecksums};
 
 #[derive(derivative::Derivative)]
 #[derivative(Debug)]
 pub struct DataSink {
   pub write_size: usize,
-  pub blake2b: blake2b_simd::State,
-  pub sha_3_512: sha3::Sha3_512,
-  pub crc32: crc32fast::Hasher,
+  pub hashers: Hashers,
   pub inode: u64,
   pub file_size: usize,
   pub position: usize,
   #[derivative(Debug="ignore")]
-  pub sink: Box<dyn AsyncWrite + Unpin + Send>,
+  pub sink: Box<dyn SinkDevice>,
   pub metadata: Option<Metadata>,
   pub estimated_size: usize,
-  pub source_channel: Receiver<BytesMut>,
+  pub source_channel: Receiver<Chunk>,
+  // output offset of the first chunk, the seek offset plus what a resumed copy had written
+  pub start: u64,
+  // puts chunks read in parallel back in order where that matters
+  pub reorder: Reorder,
+  pub path: Option<PathBuf>,
+  pub segments: Option<SegmentChecksums>,
+  pub sparse: bool,
+  pub hole_at_end: bool,
+  // bytes seeked over instead of written
+  pub hole_bytes: usize,
+  pub fsync: bool,
+  pub fdatasync: bool,
+  pub offset: u64,
+  pub reblocker: Option<Reblocker>,
+  pub journal: Option<JournalWriter>,
+  pub nocache: bool,
+  // with oflag=direct blocks are copied into `staging` and written at `position` with pwrite
+  #[derivative(Debug="ignore")]
+  pub direct: Option<Arc<std::fs::File>>,
+  pub logical_block: usize,
+  #[derivative(Debug="ignore")]
+  pub staging: Option<AlignedBuffer>,
+  // set when `writers` tasks can pwrite the output at once, run() starts them as `pool`
+  #[derivative(Debug="ignore")]
+  pub parallel: Option<Arc<std::fs::File>>,
+  pub writers: usize,
+  pub pool: Option<WriterPool>,
+  // written blocks go back here for the source to reuse
+  pub buffers: Arc<BufferPool>,
 }
 
 
 
 impl DataSink { 
   #[tracing::instrument(level="debug", ret, err)]
-  pub async fn check_permissions(sink_descriptor: &PathBuf) -> Result<(), IoError> {
+  pub async fn check_permissions(sink_descriptor: &PathBuf) -> Result<(), Failure> {
+    // a missing file is created, or refused, when it is opened
     if ! sink_descriptor.exists() {
-      tokio::fs::File::create(sink_descriptor).await.map_err(|e| IoError::InputFileOpenError(e.to_string()))?;
+      return Ok(());
     }
-    let metadata: std::fs::Metadata = tokio::fs::metadata(sink_descriptor).await.map_err(|e| IoError::InputFileOpenError(e.to_string()))?;
+    let metadata: std::fs::Metadata = tokio::fs::metadata(sink_descriptor).await.map_err(|e| IoError::OutputFileOpenError(e.to_string()).caused_by(&e))?;
     if metadata.permissions().readonly() {
-      return Err(IoError::InputFileNoReadPermission("Input file is read-only".to_string()));
+      return Err(IoError::OutputFileNoWritePermission(sink_descriptor.display().to_string()).into());
     }
     Ok(())
   }
 
-  #[tracing::instrument(skip(args, receiver), level="debug", ret, err)]
-  pub async fn new(args: &SinkConfig, receiver: Receiver<BytesMut>) -> Result<Self, IoError> {    
-    Self::check_permissions(&args.output_file).await?;
+  /// Opens the output file according to the conv flags, returns it with its metadata and
+  /// the size it had before we truncated it.
+  pub async fn open_file(path: &PathBuf, args: &SinkConfig, offset: u64) -> Result<(tokio::fs::File, Metadata, usize), Failure> {
+    Self::check_permissions(path).await?;
+    let file_size = tokio::fs::metadata(path).await.map(|m| m.len() as usize).unwrap_or(0);
     let file = tokio::fs::OpenOptions::new()
       .write(true)
-      .create(true)
-      .truncate(true)
-      .open(&args.output_file)
+      .create(args.create)
+      .create_new(args.exclusive)
+      .truncate(args.truncate && offset == 0 && args.resume_offset == 0)
+      .custom_flags(device::open_flags(args.direct, args.dsync, args.sync))
+      .open(path)
       .await
-      .map_err(|e| IoError::InputFileOpenError(e.to_string()))?;
-
-    let metadata = tokio::fs::metadata(&args.output_file).await.map_err(|e| IoError::FileMetadataAcquireError(e.to_string()))?;
-    let file_inode = metadata.ino();
-    let file_size = metadata.len() as usize;
-    let blake2b = Params::new().hash_length(64).to_state();
-    let sha_3_512 = sha3::Sha3_512::new();
-    let crc32 = crc32fast::Hasher::new();
-    let position = 0;
+      .map_err(|e| match e.raw_os_error() {
+        Some(libc::EINVAL) if args.direct => IoError::OutputFileOpenError(format!("{}: direct I/O is not supported there", path.display())),
+        _ => IoError::OutputFileOpenError(format!("{}: {}", path.display(), e)),
+      }.caused_by(&e))?;
+
+    let metadata = file.metadata().await.map_err(|e| IoError::FileMetadataAcquireError(e.to_string()).caused_by(&e))?;
+    if args.truncate && offset > 0 && args.resume_offset == 0 && metadata.is_file() {
+      // like dd, keep what lies before the seek offset
+      file.set_len(offset).await.map_err(|e| IoError::OutputFileOpenError(e.to_string()).caused_by(&e))?;
+    }
+    Ok((file, metadata, file_size))
+  }
+
+  #[tracing::instrument(skip(args, receiver), level="debug", ret, err)]
+  pub async fn new(args: &SinkConfig, receiver: Receiver<Chunk>) -> Result<Self, Failure> {    
+    let offset = (args.seek * args.block_size) as u64;
+    let start = offset + args.resume_offset as u64;
+    let mut direct = None;
+    let mut parallel = None;
+    let mut logical_block = 512;
+    let (mut sink, metadata, file_size): (Box<dyn SinkDevice>, Option<Metadata>, usize) = match &args.output_file {
+      Some(path) => {
+        let (file, metadata, file_size) = Self::open_file(path, args, offset).await?;
+        if args.direct {
+          logical_block = device::logical_block_size(file.as_raw_fd()).map_err(|e| IoError::OutputFileOpenError(e.to_string()).caused_by(&e))?;
+          if !args.block_size.is_multiple_of(logical_block) || !start.is_multiple_of(logical_block as u64) {
+            return Err(IoError::OutputFileOpenError(format!("oflag=direct needs obs and seek in multiples of the {} byte logical block size", logical_block)).into());
+          }
+          let clone = file.try_clone().await.map_err(|e| IoError::OutputFileOpenError(e.to_string()).caused_by(&e))?;
+          direct = Some(Arc::new(clone.into_std().await));
+        }
+        if args.writers > 1 && !args.sparse && args.journal.is_none() && device::is_positional(file.as_raw_fd()) {
+          parallel = match &direct {
+            Some(direct) => Some(direct.clone()),
+            None => {
+              let clone = file.try_clone().await.map_err(|e| IoError::OutputFileOpenError(e.to_string()).caused_by(&e))?;
+              Some(Arc::new(clone.into_std().await))
+            },
+          };
+        }
+        (Box::new(file), Some(metadata), file_size)
+      },
+      None => (Box::new(Stream(tokio::io::stdout())), None, 0),
+    };
+    if start > 0 {
+      match sink.seek(SeekFrom::Start(start)).await {
+        Ok(_) => {},
+        Err(e) if device::is_unseekable(&e) => {
+          // like dd, a stream is advanced by writing zero blocks
+          let zeros = vec![0u8; args.block_size];
+          for _ in 0..args.seek {
+            sink.write_all(&zeros).await.map_err(|e| IoError::OutputFileSeekError(e.to_string()).caused_by(&e))?;
+          }
+        },
+        Err(e) => return Err(IoError::OutputFileSeekError(e.to_string()).caused_by(&e)),
+      }
+    }
+    if args.writers > 1 && parallel.is_none() {
+      eprintln!("ruplica: warning: --writers needs an output file or block device without conv=sparse or a journal, writing with one task");
+    }
+    let file_inode = metadata.as_ref().map(|m| m.ino()).unwrap_or(0);
+    let hashers = args.initial_hashers.clone().unwrap_or_else(|| Hashers::new(args.hash_selection()));
+    let position = start as usize;
+    let journal = match (&args.journal, &args.output_file) {
+      (Some((journal_path, record)), Some(path)) => {
+        let mut record = record.clone();
+        record.sink = Identity::of(path)?;
+        let mut journal = JournalWriter::new(journal_path.clone(), record, args.journal_granularity);
+        // written right away so that even an early interruption can be resumed
+        journal.commit(args.resume_offset as u64)?;
+        Some(journal)
+      },
+      _ => None,
+    };
     let estimated_size = file_size;
 
     Ok(DataSink {
       write_size: args.block_size,
-      sink: Box::new(file),
-      blake2b,
-      sha_3_512,
-      crc32,
+      sink,
+      hashers,
       inode: file_inode,
       file_size,
       position,
-      metadata: Some(metadata),
+      metadata,
       estimated_size,
       source_channel: receiver,
+      start,
+      reorder: Reorder::new(0),
+      path: args.output_file.clone(),
+      segments: args.verify.then(|| args.initial_segments.clone().unwrap_or_default()),
+      sparse: args.sparse,
+      hole_at_end: false,
+      hole_bytes: 0,
+      fsync: args.fsync,
+      fdatasync: args.fdatasync,
+      offset,
+      reblocker: args.reblock.then(|| Reblocker::new(args.block_size)),
+      journal,
+      nocache: args.nocache,
+      direct,
+      logical_block,
+      staging: None,
+      parallel,
+      writers: args.writers,
+      pool: None,
+      buffers: Arc::new(BufferPool::new()),
+    })
+  }
+
+  /// Whether chunks have to be handled in output order: they are hashed, checksummed for
+  /// verification, reblocked, journaled or written by a single writer.
+  pub fn needs_order(&self) -> bool {
+    self.pool.is_none() || self.hashers.selection.any() || self.segments.is_some() || self.reblocker.is_some() || self.journal.is_some()
+  }
+
+  /// Takes a chunk from the source and writes it, or hands it to the writer pool.
+  pub async fn accept(&mut self, chunk: Chunk, statistics: &Mutex<WriteStatistics>) -> Result<(), Failure> {
+    if !self.needs_order() {
+      self.position = self.position.max((self.start + chunk.offset + chunk.length()) as usize);
+      if let Some(pool) = self.pool.as_mut() {
+        return pool.send(chunk).await;
+      }
+    }
+    for chunk in self.reorder.push(chunk) {
+      let blocks = match self.reblocker.as_mut() {
+        Some(reblocker) => reblocker.push(chunk.data),
+        None => vec![chunk.data],
+      };
+      for block in blocks {
+        if self.pool.is_some() {
+          self.queue_block(block).await?;
+          continue;
+        }
+        self.write_block(&block).await?;
+        {
+          let mut statistics = statistics.lock().await;
+          statistics.add_write(block.len() as u64, block.len() == self.write_size);
+          statistics.hole_bytes = self.hole_bytes as u64;
+        }
+        self.buffers.release(block.len());
+        self.buffers.give(block);
+      }
+    }
+    Ok(())
+  }
+
+  /// Hands the next output block to the writer pool, hashing it on the way.
+  async fn queue_block(&mut self, block: BytesMut) -> Result<(), Failure> {
+    self.hashers.update(&block);
+    if let Some(segments) = self.segments.as_mut() {
+      segments.update(&block);
+    }
+    let offset = self.position as u64 - self.start;
+    self.position += block.len();
+    match self.pool.as_mut() {
+      Some(pool) => pool.send(Chunk::new(block, offset)).await,
+      None => Ok(()),
+    }
+  }
+
+  /// Writes one output block, or leaves a hole for it in sparse mode.
+  pub async fn write_block(&mut self, block: &[u8]) -> Result<(), Failure> {
+    tracing::debug!("Writing packet of {} bytes", block.len());
+    let mut skipped = false;
+    if self.sparse && block.iter().all(|b| *b == 0) {
+      match self.skip_hole(block.len()).await {
+        Ok(()) => skipped = true,
+        Err(e) => {
+          tracing::debug!("Output is not seekable ({}), writing zeros instead", e);
+          self.sparse = false;
+        }
+      }
+    }
+    match skipped {
+      true => self.hole_bytes += block.len(),
+      false => self.write_data(block).await.map_err(|e| Failure::write(&e, self.position))?,
+    }
+    if self.nocache && !skipped {
+      // only drops what was written back already, finish() takes care of the rest
+      device::drop_cache(self.sink.as_raw_fd(), self.position as u64, block.len() as u64);
+    }
+    self.hole_at_end = skipped;
+    self.hashers.update(block);
+    if let Some(segments) = self.segments.as_mut() {
+      segments.update(block);
+    }
+    if let Some(journal) = self.journal.as_mut() {
+      journal.update(block);
+    }
+    self.position += block.len();
+    Ok(())
+  }
+
+  /// Writes `block` at the current position, through an aligned buffer with oflag=direct.
+  async fn write_data(&mut self, block: &[u8]) -> std::io::Result<()> {
+    let Some(file) = self.direct.clone() else {
+      return self.sink.write_all(block).await;
+    };
+    if !block.len().is_multiple_of(self.logical_block) {
+      // like dd, the unaligned tail is written through the page cache
+      device::set_direct(file.as_raw_fd(), false)?;
+    }
+    let mut staging = match self.staging.take() {
+      Some(staging) if staging.len() >= block.len() => staging,
+      _ => AlignedBuffer::new(block.len().next_multiple_of(DIRECT_ALIGNMENT), DIRECT_ALIGNMENT),
+    };
+    staging.as_mut_slice()[..block.len()].copy_from_slice(block);
+    let (length, offset) = (block.len(), self.position as u64);
+    let (staging, written) = tokio::task::spawn_blocking(move || {
+      let written = file.write_all_at(&staging.as_slice()[..length], offset);
+      (staging, written)
     })
+    .await?;
+    self.staging = Some(staging);
+    written
+  }
+
+  /// Seeks over `length` bytes instead of writing them, leaving a hole in the output.
+  pub async fn skip_hole(&mut self, length: usize) -> std::io::Result<()> {
+    if self.direct.is_some() {
+      // direct writes go to explicit offsets, there is nothing to move
+      return Ok(());
+    }
+    self.sink.flush().await?;
+    self.sink.seek(SeekFrom::Current(length as i64)).await?;
+    Ok(())
+  }
+
+  /// Syncs the output and records it in the journal when a commit is due.
+  pub async fn checkpoint(&mut self) -> Result<(), Failure> {
+    let written = self.position as u64 - self.offset;
+    if !self.journal.as_ref().is_some_and(|journal| journal.due(written)) {
+      return Ok(());
+    }
+    self.sink.flush().await.map_err(|e| Failure::write(&e, self.position))?;
+    match device::fdatasync(self.sink.as_raw_fd()) {
+      Err(e) if e.raw_os_error() != Some(libc::EINVAL) => return Err(Failure::write(&e, self.position)),
+      _ => {},
+    }
+    if let Some(journal) = self.journal.as_mut() {
+      journal.commit(written)?;
+    }
+    Ok(())
+  }
+
+  /// Writes the short last block, flushes the output, fixes up its length after a trailing hole
+  /// and syncs it if asked to.
+  pub async fn finish(&mut self) -> Result<(), Failure> {
+    if let Some(tail) = self.reblocker.as_mut().and_then(|reblocker| reblocker.finish()) {
+      match self.pool.is_some() {
+        true => self.queue_block(tail).await?,
+        false => {
+          self.write_block(&tail).await?;
+          self.buffers.release(tail.len());
+        },
+      }
+    }
+    if let Some(mut pool) = self.pool.take() {
+      pool.finish().await?;
+    }
+    self.sink.flush().await.map_err(|e| Failure::write(&e, self.position))?;
+    if self.hole_at_end {
+      device::extend_to(self.sink.as_raw_fd(), self.position as u64).map_err(|e| Failure::write(&e, self.position))?;
+    }
+    let synced = match (self.fsync, self.fdatasync) {
+      (true, _) => device::fsync(self.sink.as_raw_fd()),
+      (false, true) => device::fdatasync(self.sink.as_raw_fd()),
+      (false, false) => Ok(()),
+    };
+    match synced {
+      // pipes and terminals cannot be synced, dd ignores that too
+      Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {},
+      Err(e) => return Err(Failure::write(&e, self.position)),
+      Ok(()) => {},
+    }
+    if self.nocache {
+      // written back pages can be dropped, so write them back first
+      let _ = device::fdatasync(self.sink.as_raw_fd());
+      device::drop_cache(self.sink.as_raw_fd(), 0, 0);
+    }
+    self.sink.shutdown().await.map_err(|e| Failure::write(&e, self.position))
   }
 
   // start a consumer thread
   #[tracing::instrument(skip(source_channel, config, dd_context), level="debug", ret, err)]
-  pub async fn run(source_channel: tokio::sync::mpsc::Receiver<BytesMut>, config: SinkConfig, dd_context: Arc<Mutex<DdContext>>) -> Result<(), IoError> {
+  pub async fn run(source_channel: tokio::sync::mpsc::Receiver<Chunk>, config: SinkConfig, dd_context: Arc<Mutex<DdContext>>) -> Result<(), Failure> {
     tracing::info!("Preparing to write data");    
+    let mut data_sink: DataSink = DataSink::new(&config, source_channel).await?;
+    data_sink.buffers = dd_context.lock().await.buffers.clone();
     let task =  {
       dd_context.lock().await.new_task("DataSink").await
     };
@@ -95,58 +396,89 @@ impl DataSink {
       dd_context.lock().await.write_statistics.clone()
     };
     statistics.lock().await.init();
+    if let Some(file) = data_sink.parallel.take() {
+      let logical_block = data_sink.direct.is_some().then_some(data_sink.logical_block);
+      let pool = WriterPool::spawn(file, data_sink.writers, data_sink.start, data_sink.write_size, logical_block, data_sink.nocache, statistics.clone(), data_sink.buffers.clone());
+      data_sink.pool = Some(pool);
+    }
 
     tracing::debug!("Spawning sink thread");
     tokio::spawn(async move {
-      let mut data_sink: DataSink = DataSink::new(&config, source_channel).await.unwrap();
       let main_notifications = dd_context.lock().await.main_notifications.clone();
-      let sink_notification = dd_context.lock().await.sink_notifications.clone();
-      let source_notification = dd_context.lock().await.source_notifications.clone();
+      let digests = dd_context.lock().await.sink_digests.clone();
+      let verify_report = dd_context.lock().await.verify_report.clone();
+      let failure = dd_context.lock().await.failure.clone();
       task.lock().await.change_state(statistics::TaskStatus::Running);
 
       tracing::info!("Reporting readiness");
       tracing::info!("Started writing data");
-      loop {
+      let outcome: Result<(), Failure> = 'receive: loop {
         tracing::debug!("Waiting for data");
-        match data_sink.source_channel.try_recv() {
-          Ok(v) => {
+        match data_sink.source_channel.recv().await {
+          Some(chunk) => {
             tracing::debug!("Received data");
-            if v.is_empty() {
+            if chunk.is_end() {
               tracing::warn!("Received empty data, exiting");
               // empty data, exit
-              data_sink.sink.shutdown().await.unwrap();
-              sink_notification.notified().await;
-              break;
+              let before = data_sink.position;
+              // the writer pool counts what it writes itself
+              let pooled = data_sink.pool.is_some();
+              if let Err(e) = data_sink.finish().await {
+                break 'receive Err(e);
+              }
+              // the copy is complete, there is nothing left to resume
+              if let Some(journal) = data_sink.journal.take() {
+                if let Err(e) = journal.remove() {
+                  break 'receive Err(e.into());
+                }
+              }
+              if data_sink.position > before && !pooled {
+                // the short tail the reblocker held back
+                statistics.lock().await.add_write((data_sink.position - before) as u64, false);
+              }
+              statistics.lock().await.hole_bytes = data_sink.hole_bytes as u64;
+              *digests.lock().await = data_sink.hashers.digests();
+              if let (Some(segments), Some(path)) = (data_sink.segments.take(), data_sink.path.clone()) {
+                let written = data_sink.position as u64 - data_sink.offset;
+                tracing::info!("Reading back {} written bytes", written);
+                let selection = data_sink.hashers.selection;
+                match verify::verify(path, data_sink.offset, written, segments.finish(), selection).await {
+                  Ok(report) => *verify_report.lock().await = Some(report),
+                  // an output that cannot be read back is not verified
+                  Err(e) => break Err(Failure::from(e)),
+                }
+              }
+              break Ok(());
             } else {
-              tracing::debug!("Writing packet of {} bytes", v.len());
-              data_sink.sink.write_all(&v).await.unwrap();
-              // data_sink.sink.flush().await.unwrap();
-              data_sink.position += v.len();
+              if let Err(e) = data_sink.accept(chunk, &statistics).await {
+                break 'receive Err(e);
+              }
+              if let Err(e) = data_sink.checkpoint().await {
+                break 'receive Err(e);
+              }
               task.lock().await.ping();
               // notification.notified().await;
             }
           },
-          Err(e) => {
-            match e {
-              tokio::sync::mpsc::error::TryRecvError::Empty => {
-                // tracing::debug!("No data available, waiting for notification");
-                sleep(tokio::time::Duration::from_millis(10));
-                continue;
-              },
-              tokio::sync::mpsc::error::TryRecvError::Disconnected => {
-                data_sink.source_channel.close();
-                tracing::debug!("Channel closed, exiting");
-                break;
-              },             
-            } 
+          None => {
+            // the source stopped without reaching the end, keep what was written so far
+            tracing::debug!("Channel closed, exiting");
+            break data_sink.finish().await;
           }
         }
+      };
+      match outcome {
+        Ok(()) => task.lock().await.complete(0),
+        Err(e) => {
+          tracing::error!("Writing failed: {}", e);
+          statistics.lock().await.add_error();
+          task.lock().await.fail(-2, e.to_string());
+          DdContext::record_failure(&failure, e).await;
+          // stops the source, its sends fail from now on
+          data_sink.source_channel.close();
+        }
       }
-      loop {
-        tracing::debug!("relying on the notification to exit");
-        std::thread::sleep(tokio::time::Duration::from_millis(250));
-        notification.notify_waiters();
-      }
+      main_notifications.notify_one();
     });
     Ok(())
   }
@@ -166,12 +498,14 @@ mod tests {
     file.write_all("asdfasdasrd".as_bytes()).await.unwrap();
 
     let config = SinkConfig {
-      output_file: file_path.clone(),
+      output_file: Some(file_path.clone()),
       block_size: 512,
       enable_hash: false,
       enable_crc32: false,
       enable_sha3: false,
       enable_blake2b: false,
+      verify: false,
+      ..SinkConfig::default()
     };
 
     let (_sink,source) = tokio::sync::mpsc::channel(1);
@@ -179,4 +513,92 @@ mod tests {
     assert_eq!(sink.file_size, 11);
     assert_eq!(sink.inode, file_path.metadata().unwrap().ino());
   }
+
+  #[tokio::test]
+  async fn test_sink_notrunc() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("test_sink_notrunc");
+    std::fs::write(&file_path, "asdfasdasrd").unwrap();
+
+    let config = SinkConfig { output_file: Some(file_path.clone()), truncate: false, ..SinkConfig::default() };
+    let (_sink, source) = tokio::sync::mpsc::channel(1);
+    DataSink::new(&config, source).await.unwrap();
+    assert_eq!(std::fs::read(&file_path).unwrap(), b"asdfasdasrd");
+  }
+
+  #[tokio::test]
+  async fn test_sink_direct_with_unaligned_tail() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("direct");
+    let config = SinkConfig { output_file: Some(file_path.clone()), block_size: 4096, ..SinkConfig::default() };
+    let (_sink, source) = tokio::sync::mpsc::channel(1);
+    let mut sink = DataSink::new(&config, source).await.unwrap();
+    // tmpfs refuses O_DIRECT, a plain handle goes through the same staging and pwrite path
+    sink.direct = Some(Arc::new(std::fs::File::options().write(true).open(&file_path).unwrap()));
+    sink.logical_block = 4096;
+    let data: Vec<u8> = (0..4096 + 100).map(|i| i as u8).collect();
+    sink.write_block(&data[..4096]).await.unwrap();
+    let staging = sink.staging.as_ref().unwrap();
+    assert!((staging.as_slice().as_ptr() as usize).is_multiple_of(DIRECT_ALIGNMENT));
+    sink.write_block(&data[4096..]).await.unwrap();
+    sink.finish().await.unwrap();
+    assert_eq!(std::fs::read(&file_path).unwrap(), data);
+  }
+
+  #[tokio::test]
+  #[ignore = "needs a filesystem that supports O_DIRECT, tmpfs does not"]
+  async fn test_sink_direct_checks_alignment() {
+    let dir = tempdir().unwrap();
+    let config = SinkConfig { output_file: Some(dir.path().join("direct")), block_size: 4096, direct: true, ..SinkConfig::default() };
+    let (_sink, source) = tokio::sync::mpsc::channel(1);
+    assert!(DataSink::new(&config, source).await.unwrap().direct.is_some());
+
+    let config = SinkConfig { block_size: 1000, ..config };
+    let (_sink, source) = tokio::sync::mpsc::channel(1);
+    assert!(matches!(DataSink::new(&config, source).await, Err(Failure { error: IoError::OutputFileOpenError(_), .. })));
+  }
+
+  #[tokio::test]
+  async fn test_sink_excl_and_nocreat() {
+    let dir = tempdir().unwrap();
+    let existing = dir.path().join("existing");
+    std::fs::write(&existing, "x").unwrap();
+    let missing = dir.path().join("missing");
+
+    let config = SinkConfig { output_file: Some(existing), exclusive: true, ..SinkConfig::default() };
+    let (_sink, source) = tokio::sync::mpsc::channel(1);
+    assert!(matches!(DataSink::new(&config, source).await, Err(Failure { error: IoError::OutputFileOpenError(_), .. })));
+
+    let config = SinkConfig { output_file: Some(missing.clone()), create: false, ..SinkConfig::default() };
+    let (_sink, source) = tokio::sync::mpsc::channel(1);
+    assert!(matches!(DataSink::new(&config, source).await, Err(Failure { error: IoError::OutputFileOpenError(_), .. })));
+    assert!(!missing.exists());
+  }
+
+  #[tokio::test]
+  async fn test_sink_reports_enospc() {
+    let config = SinkConfig { output_file: Some(PathBuf::from("/dev/full")), ..SinkConfig::default() };
+    let context = Arc::new(Mutex::new(DdContext::new()));
+    let (sender, receiver) = tokio::sync::mpsc::channel(10);
+    DataSink::run(receiver, config, context.clone()).await.unwrap();
+    for offset in (0..2048).step_by(512) {
+      if sender.send(Chunk::new(BytesMut::zeroed(512), offset)).await.is_err() {
+        break;
+      }
+    }
+    let _ = sender.send(Chunk::end(2048)).await;
+    sender.closed().await;
+    let notifications = context.lock().await.main_notifications.clone();
+    while context.lock().await.are_tasks_pending().await {
+      notifications.notified().await;
+    }
+
+    let failure = context.lock().await.failure.lock().await.clone();
+    let failure = failure.unwrap();
+    assert!(matches!(failure.error, IoError::OutputNoSpace(_)), "{:?}", failure);
+    assert_eq!(failure.errno, Some(libc::ENOSPC));
+    let task = context.lock().await.task_status["DataSink"].clone();
+    assert!(matches!(task.lock().await.status, statistics::TaskStatus::Failed(_)));
+    assert_eq!(context.lock().await.write_statistics.lock().await.total_errors, 1);
+  }
 }
@@ -1,2 +1,4 @@
 pub mod core;
-pub mod config;
\ No newline at end of file
+pub mod config;
+pub mod pool;
+pub mod verify;
@@ -0,0 +1,137 @@
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::FileExt;
+use std::sync::Arc;
+
+use tokio::sync::mpsc::Sender;
+use tokio::sync::Mutex;
+use tokio::task::JoinSet;
+
+use crate::environment::statistics::WriteStatistics;
+use crate::io::aligned::{AlignedBuffer, DIRECT_ALIGNMENT};
+use crate::io::buffers::BufferPool;
+use crate::io::chunk::Chunk;
+use crate::io::device;
+use crate::io::error::{Failure, IoError};
+
+/// Writer tasks taking chunks from one queue and writing each at its offset with pwrite, so that
+/// several writes to a file or block device are in flight at once.
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+pub struct WriterPool {
+  #[derivative(Debug="ignore")]
+  queue: Option<Sender<Chunk>>,
+  #[derivative(Debug="ignore")]
+  writers: JoinSet<Result<(), Failure>>,
+}
+
+impl WriterPool {
+  /// Starts `writers` tasks writing chunk offset 0 at `start`. `logical_block` is set when the
+  /// output is open with O_DIRECT, chunks are then copied to aligned buffers first. Written
+  /// chunks go back to `buffers`.
+  #[allow(clippy::too_many_arguments)]
+  pub fn spawn(file: Arc<std::fs::File>, writers: usize, start: u64, write_size: usize, logical_block: Option<usize>, nocache: bool, statistics: Arc<Mutex<WriteStatistics>>, buffers: Arc<BufferPool>) -> Self {
+    let (queue, received) = tokio::sync::mpsc::channel::<Chunk>(writers);
+    let received = Arc::new(Mutex::new(received));
+    let mut tasks = JoinSet::new();
+    for _ in 0..writers {
+      let (file, received, statistics, buffers) = (file.clone(), received.clone(), statistics.clone(), buffers.clone());
+      tasks.spawn(async move {
+        let mut staging = None;
+        loop {
+          let Some(chunk) = received.lock().await.recv().await else {
+            break;
+          };
+          let (position, length) = (start + chunk.offset, chunk.data.len());
+          let writing = file.clone();
+          let (data, written);
+          (data, staging, written) = tokio::task::spawn_blocking(move || {
+            let (staging, written) = write_chunk(&writing, &chunk.data, position, logical_block, staging);
+            (chunk.data, staging, written)
+          })
+            .await
+            .map_err(|e| Failure::from(IoError::WriteError(format!("writer failed: {}", e))))?;
+          if let Err(e) = written {
+            // the sink notices on its next send
+            received.lock().await.close();
+            return Err(Failure::write(&e, position as usize));
+          }
+          if nocache {
+            device::drop_cache(file.as_raw_fd(), position, length as u64);
+          }
+          statistics.lock().await.add_write(length as u64, length == write_size);
+          buffers.release(length);
+          buffers.give(data);
+        }
+        Ok(())
+      });
+    }
+    WriterPool { queue: Some(queue), writers: tasks }
+  }
+
+  /// Queues `chunk` for the next free writer, fails with the writer's error once one failed.
+  pub async fn send(&mut self, chunk: Chunk) -> Result<(), Failure> {
+    let sent = match self.queue.as_ref() {
+      Some(queue) => queue.send(chunk).await.is_ok(),
+      None => false,
+    };
+    if sent {
+      return Ok(());
+    }
+    self.finish().await?;
+    Err(Failure::from(IoError::ChannelEror("writers stopped accepting data".to_string())))
+  }
+
+  /// Waits until everything queued was written, returns the first failure of a writer.
+  pub async fn finish(&mut self) -> Result<(), Failure> {
+    self.queue = None;
+    let mut outcome = Ok(());
+    while let Some(joined) = self.writers.join_next().await {
+      let joined = joined.unwrap_or_else(|e| Err(Failure::from(IoError::WriteError(format!("writer failed: {}", e)))));
+      if let (Ok(()), Err(e)) = (&outcome, joined) {
+        outcome = Err(e);
+      }
+    }
+    outcome
+  }
+}
+
+/// Writes `data` at `position`, through `staging` when the output is open with O_DIRECT.
+fn write_chunk(file: &std::fs::File, data: &[u8], position: u64, logical_block: Option<usize>, staging: Option<AlignedBuffer>) -> (Option<AlignedBuffer>, std::io::Result<()>) {
+  let Some(block) = logical_block else {
+    return (staging, file.write_all_at(data, position));
+  };
+  if !data.len().is_multiple_of(block) {
+    // like dd, the unaligned tail is written through the page cache
+    if let Err(e) = device::set_direct(file.as_raw_fd(), false) {
+      return (staging, Err(e));
+    }
+  }
+  let mut staging = match staging {
+    Some(staging) if staging.len() >= data.len() => staging,
+    _ => AlignedBuffer::new(data.len().next_multiple_of(DIRECT_ALIGNMENT), DIRECT_ALIGNMENT),
+  };
+  staging.as_mut_slice()[..data.len()].copy_from_slice(data);
+  let written = file.write_all_at(&staging.as_slice()[..data.len()], position);
+  (Some(staging), written)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use bytes::BytesMut;
+
+  #[tokio::test]
+  async fn test_pool_writes_chunks_at_their_offset() {
+    let file = tempfile::NamedTempFile::new().unwrap();
+    let output = Arc::new(std::fs::OpenOptions::new().write(true).open(file.path()).unwrap());
+    let statistics = Arc::new(Mutex::new(WriteStatistics::default()));
+    let mut pool = WriterPool::spawn(output, 3, 2, 4, None, false, statistics.clone(), Arc::new(BufferPool::new()));
+    for (data, offset) in [(&b"ijkl"[..], 8), (b"abcd", 0), (b"mn", 12), (b"efgh", 4)] {
+      pool.send(Chunk::new(BytesMut::from(data), offset)).await.unwrap();
+    }
+    pool.finish().await.unwrap();
+    assert_eq!(std::fs::read(file.path()).unwrap(), b"\0\0abcdefghijklmn");
+    let statistics = statistics.lock().await;
+    assert_eq!((statistics.full_records, statistics.partial_records), (3, 1));
+  }
+}
@@ -0,0 +1,200 @@
+use std::fs::File;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+use crate::io::aligned::{AlignedBuffer, DIRECT_ALIGNMENT};
+use crate::io::device;
+use crate::io::digest::{Digests, HashSelection, Hashers};
+use crate::io::error::IoError;
+
+/// Granularity at which read-back verification can locate corruption.
+pub const VERIFY_SEGMENT: usize = 1 << 20;
+
+/// CRC32 of every `VERIFY_SEGMENT` bytes of a stream.
+#[derive(Debug, Clone, Default)]
+pub struct SegmentChecksums {
+  checksums: Vec<u32>,
+  current: crc32fast::Hasher,
+  fill: usize,
+}
+
+impl SegmentChecksums {
+  pub fn new() -> Self {
+    SegmentChecksums::default()
+  }
+
+  pub fn update(&mut self, mut data: &[u8]) {
+    while !data.is_empty() {
+      let take = (VERIFY_SEGMENT - self.fill).min(data.len());
+      self.current.update(&data[..take]);
+      self.fill += take;
+      data = &data[take..];
+      if self.fill == VERIFY_SEGMENT {
+        self.checksums.push(std::mem::take(&mut self.current).finalize());
+        self.fill = 0;
+      }
+    }
+  }
+
+  pub fn finish(mut self) -> Vec<u32> {
+    if self.fill > 0 {
+      self.checksums.push(self.current.finalize());
+    }
+    self.checksums
+  }
+}
+
+/// Outcome of re-reading the written range of the output.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct VerifyReport {
+  pub offset: u64,
+  pub bytes_verified: u64,
+  pub direct_io: bool,
+  pub digests: Digests,
+  /// start of the first `VERIFY_SEGMENT` whose contents differ from what was written
+  pub first_mismatch: Option<u64>,
+}
+
+fn hash_range(file: &File, offset: u64, length: u64, selection: HashSelection) -> std::io::Result<(Hashers, SegmentChecksums, u64)> {
+  let mut buffer = AlignedBuffer::new(VERIFY_SEGMENT, DIRECT_ALIGNMENT);
+  let mut hashers = Hashers::new(selection);
+  let mut segments = SegmentChecksums::new();
+  let end = offset + length;
+  // O_DIRECT needs aligned offsets, so start at the enclosing block and cut the head off
+  let mut position = offset - offset % DIRECT_ALIGNMENT as u64;
+  let mut verified = 0;
+  while position < end {
+    let read = file.read_at(buffer.as_mut_slice(), position)?;
+    if read == 0 {
+      break;
+    }
+    let from = offset.saturating_sub(position) as usize;
+    let to = (end - position).min(read as u64) as usize;
+    if from < to {
+      hashers.update(&buffer.as_slice()[from..to]);
+      segments.update(&buffer.as_slice()[from..to]);
+      verified += (to - from) as u64;
+    }
+    position += read as u64;
+  }
+  Ok((hashers, segments, verified))
+}
+
+/// Re-reads `length` bytes at `offset` of `path`, bypassing the page cache where possible,
+/// and compares them against the segment checksums recorded while writing.
+pub fn read_back(path: &Path, offset: u64, length: u64, expected: &[u32], selection: HashSelection) -> Result<VerifyReport, IoError> {
+  let buffered = File::open(path).map_err(|e| IoError::VerifyError(e.to_string()))?;
+  buffered.sync_data().map_err(|e| IoError::VerifyError(e.to_string()))?;
+
+  let direct = std::fs::OpenOptions::new()
+    .read(true)
+    .custom_flags(libc::O_DIRECT)
+    .open(path)
+    .and_then(|file| hash_range(&file, offset, length, selection));
+  let (direct_io, (hashers, segments, verified)) = match direct {
+    Ok(result) => (true, result),
+    Err(e) => {
+      tracing::debug!("direct read-back unavailable ({}), dropping cached pages instead", e);
+      // only clean pages are dropped, which is why the file was synced first
+      device::drop_cache(buffered.as_raw_fd(), offset, length);
+      let result = hash_range(&buffered, offset, length, selection).map_err(|e| IoError::VerifyError(e.to_string()))?;
+      (false, result)
+    }
+  };
+
+  let actual = segments.finish();
+  let first_mismatch = (0..expected.len().max(actual.len()))
+    .find(|i| expected.get(*i) != actual.get(*i))
+    .map(|i| offset + (i * VERIFY_SEGMENT) as u64);
+
+  Ok(VerifyReport {
+    offset,
+    bytes_verified: verified,
+    direct_io,
+    digests: hashers.digests(),
+    first_mismatch,
+  })
+}
+
+#[tracing::instrument(skip(expected), level="debug", err)]
+pub async fn verify(path: PathBuf, offset: u64, length: u64, expected: Vec<u32>, selection: HashSelection) -> Result<VerifyReport, IoError> {
+  tokio::task::spawn_blocking(move || read_back(&path, offset, length, &expected, selection))
+    .await
+    .map_err(|e| IoError::VerifyError(e.to_string()))?
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn pattern(length: usize) -> Vec<u8> {
+    (0..length).map(|i| (i % 251) as u8).collect()
+  }
+
+  fn checksums(data: &[u8]) -> Vec<u32> {
+    let mut segments = SegmentChecksums::new();
+    // uneven pieces to exercise segment boundaries
+    for piece in data.chunks(4099) {
+      segments.update(piece);
+    }
+    segments.finish()
+  }
+
+  #[test]
+  fn test_segment_checksums() {
+    let data = pattern(VERIFY_SEGMENT * 2 + 10);
+    let segments = checksums(&data);
+    assert_eq!(segments.len(), 3);
+    assert_eq!(segments[2], crc32fast::hash(&data[VERIFY_SEGMENT * 2..]));
+  }
+
+  #[test]
+  fn test_read_back_matches() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("out");
+    let data = pattern(VERIFY_SEGMENT + 1234);
+    std::fs::write(&path, &data).unwrap();
+    let selection = HashSelection { crc32: true, ..HashSelection::default() };
+    let report = read_back(&path, 0, data.len() as u64, &checksums(&data), selection).unwrap();
+    assert_eq!(report.bytes_verified, data.len() as u64);
+    assert_eq!(report.first_mismatch, None);
+    assert_eq!(report.digests.crc32, Some(format!("{:08x}", crc32fast::hash(&data))));
+  }
+
+  #[test]
+  fn test_read_back_at_offset() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("out");
+    let data = pattern(5000);
+    let mut file = vec![0xffu8; 777];
+    file.extend_from_slice(&data);
+    std::fs::write(&path, &file).unwrap();
+    let report = read_back(&path, 777, data.len() as u64, &checksums(&data), HashSelection::default()).unwrap();
+    assert_eq!(report.bytes_verified, data.len() as u64);
+    assert_eq!(report.first_mismatch, None);
+  }
+
+  #[test]
+  fn test_read_back_locates_corruption() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("out");
+    let data = pattern(VERIFY_SEGMENT * 3);
+    let mut corrupted = data.clone();
+    corrupted[VERIFY_SEGMENT * 2 + 17] ^= 0xff;
+    std::fs::write(&path, &corrupted).unwrap();
+    let report = read_back(&path, 0, data.len() as u64, &checksums(&data), HashSelection::default()).unwrap();
+    assert_eq!(report.first_mismatch, Some((VERIFY_SEGMENT * 2) as u64));
+  }
+
+  #[test]
+  fn test_read_back_truncated_output() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("out");
+    let data = pattern(VERIFY_SEGMENT * 2);
+    std::fs::write(&path, &data[..VERIFY_SEGMENT + 5]).unwrap();
+    let report = read_back(&path, 0, data.len() as u64, &checksums(&data), HashSelection::default()).unwrap();
+    assert_eq!(report.bytes_verified, (VERIFY_SEGMENT + 5) as u64);
+    assert_eq!(report.first_mismatch, Some(VERIFY_SEGMENT as u64));
+  }
+}
@@ -1,27 +1,96 @@
 use std::path::PathBuf;
 
+use crate::config::{Conv, Flag, SourceChange};
+use crate::io::digest::{HashSelection, Hashers};
+
 #[derive(derivative::Derivative)]
 #[derivative(Default)]
 pub struct SourceConfig {
-  pub input_file: PathBuf,
+  // None reads from stdin
+  pub input_file: Option<PathBuf>,
   #[derivative(Default(value = "512"))]
   pub buffer_size: usize,
   #[derivative(Default(value = "512"))]
   pub block_size: usize,
+  #[derivative(Default(value = "false"))]
+  pub enable_hash: bool,
+  #[derivative(Default(value = "false"))]
+  pub enable_crc32: bool,
+  #[derivative(Default(value = "false"))]
+  pub enable_sha3: bool,
+  #[derivative(Default(value = "false"))]
+  pub enable_blake2b: bool,
+  #[derivative(Default(value = "false"))]
+  pub noerror: bool,
+  #[derivative(Default(value = "false"))]
+  pub pad_blocks: bool,
+  // iflag=direct, dsync, sync and nocache
+  #[derivative(Default(value = "false"))]
+  pub direct: bool,
+  #[derivative(Default(value = "false"))]
+  pub dsync: bool,
+  #[derivative(Default(value = "false"))]
+  pub sync: bool,
+  #[derivative(Default(value = "false"))]
+  pub nocache: bool,
+  // keep reading until block_size bytes came in or the input ended
+  #[derivative(Default(value = "false"))]
+  pub fullblock: bool,
+  // send zeros for holes in the input instead of reading them
+  #[derivative(Default(value = "false"))]
+  pub sparse: bool,
+  // in blocks of block_size
+  pub skip: usize,
+  pub count: Option<usize>,
+  // bytes after the skip offset an interrupted run already copied
+  pub resume_offset: usize,
+  // hash state of those bytes
+  pub initial_hashers: Option<Hashers>,
+  #[derivative(Default(value = "SourceChange::Warn"))]
+  pub on_change: SourceChange,
+  // reader tasks for a seekable input, a stream is always read by one
+  #[derivative(Default(value = "1"))]
+  pub readers: usize,
 }
 
 impl SourceConfig {
   pub fn new() -> Self {
     SourceConfig::default()
   }
+
+  pub fn hash_selection(&self) -> HashSelection {
+    HashSelection {
+      blake2b: self.enable_blake2b,
+      sha3: self.enable_sha3,
+      crc32: self.enable_crc32,
+    }
+  }
 }
 
 impl From<&crate::config::Args> for SourceConfig {
   fn from(args: &crate::config::Args) -> Self {
+    let hashes = args.hash_selection();
     SourceConfig {
-      input_file: PathBuf::from(args.input_file.clone().unwrap_or("".to_string())),
-      buffer_size: args.bs,
-      block_size: args.bs,
+      input_file: args.input_file.clone().map(PathBuf::from),
+      buffer_size: args.ibs(),
+      block_size: args.ibs(),
+      enable_hash: hashes.any(),
+      enable_crc32: hashes.crc32,
+      enable_sha3: hashes.sha3,
+      enable_blake2b: hashes.blake2b,
+      noerror: args.has_conv(Conv::Noerror),
+      pad_blocks: args.has_conv(Conv::Sync),
+      sparse: args.has_conv(Conv::Sparse),
+      direct: args.has_iflag(Flag::Direct),
+      dsync: args.has_iflag(Flag::Dsync),
+      sync: args.has_iflag(Flag::Sync),
+      nocache: args.has_iflag(Flag::Nocache),
+      fullblock: args.has_iflag(Flag::Fullblock),
+      skip: args.skip,
+      count: args.count,
+      on_change: args.on_source_change,
+      readers: args.readers,
+      ..SourceConfig::default()
     }
   }
-}
\ No newline at end of file
+}
@@ -1,82 +1,454 @@
 
+use std::collections::BTreeSet;
+use std::io::SeekFrom;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::FileExt;
 use std::sync::Arc;
 use std::{os::unix::fs::MetadataExt, path::PathBuf};
 
-use bytes::BytesMut;
+use bytes::{BufMut, BytesMut};
 // use sha3::digest::core_api::Buffer;
-use tokio::io::{AsyncRead, AsyncReadExt};
-use blake2b_simd::Params;
-use sha3::Digest;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::mpsc::error::TrySendError;
 use tokio::sync::mpsc::Sender;
 use tokio::sync::Mutex;
 
-use crate::environment::statistics::DdContext;
-use crate::io::error::IoError;
+use crate::environment::statistics::{DdContext, ReadStatistics, Task};
+use crate::io::aligned::DIRECT_ALIGNMENT;
+use crate::io::buffers::BufferPool;
+use crate::io::chunk::{Chunk, Reorder};
+use crate::io::device::{self, SourceDevice, Stream};
+use crate::io::digest::Hashers;
+use crate::io::error::{Failure, IoError};
 use crate::io::source::config::SourceConfig;
+use crate::io::source::snapshot::SourceWatch;
+use crate::io::throttle::Throttle;
 
 #[derive(derivative::Derivative)]
 #[derivative(Debug)]
 pub struct DataSource {
   pub read_size: usize,
   #[derivative(Debug="ignore")]
-  pub source: Box<dyn AsyncRead + Unpin + Send>,
-  pub blake2b: blake2b_simd::State,
-  pub sha_3_512: sha3::Sha3_512,
-  pub crc32: crc32fast::Hasher,
+  pub source: Box<dyn SourceDevice>,
+  pub hashers: Hashers,
   pub inode: u64,
   pub file_size: usize,
   pub position: usize,
   pub estimated_size: usize,
-  pub sink_channel: Sender<BytesMut>,
+  pub sink_channel: Sender<Chunk>,
+  // output offset of the next chunk, what was sent so far
+  pub sent: u64,
+  // sends that found the queue to the sink full
+  pub queue_stalls: u64,
+  pub buffers: Arc<BufferPool>,
+  pub throttle: Arc<Throttle>,
+  pub noerror: bool,
+  pub pad_blocks: bool,
+  pub sparse: bool,
+  pub fullblock: bool,
+  // reads that returned less than asked for, fullblock joins them into whole blocks
+  pub short_reads: u64,
+  // without fullblock a short read that is not the end of the input makes count count reads,
+  // which is worth one warning
+  pub warn_partial_read: bool,
+  pub partial_read: Option<usize>,
+  pub nocache: bool,
+  // with iflag=direct blocks are read at `position` with pread, past tokio's unaligned buffers
+  #[derivative(Debug="ignore")]
+  pub direct: Option<Arc<std::fs::File>>,
+  // the data extent at or after the position, (MAX, MAX) once only a hole is left
+  pub data_extent: (u64, u64),
+  pub remaining_blocks: Option<usize>,
+  // streams are not watched for changes
+  pub watch: Option<SourceWatch>,
+  pub readers: usize,
+  // set when `readers` tasks can pread the input at once
+  #[derivative(Debug="ignore")]
+  pub parallel: Option<Arc<std::fs::File>>,
 } 
 
 
 
 impl DataSource {
   #[tracing::instrument(level="debug", ret, err)]
-  pub async fn check_permissions(source_descriptor: &PathBuf) -> Result<(), IoError> {
-    let metadata = tokio::fs::metadata(source_descriptor).await.map_err(|e| IoError::InputFileOpenError(e.to_string()))?;
-    if metadata.permissions().readonly() {
-      return Err(IoError::InputFileNoReadPermission("Input file is read-only".to_string()));
-    }
+  pub async fn check_permissions(source_descriptor: &PathBuf) -> Result<(), Failure> {
+    // a read-only input is fine, lack of read access shows up when opening it
+    tokio::fs::metadata(source_descriptor).await.map_err(|e| match e.kind() {
+      std::io::ErrorKind::NotFound => IoError::InputFileDoesNotExist(source_descriptor.display().to_string()),
+      _ => IoError::InputFileOpenError(e.to_string()),
+    }.caused_by(&e))?;
     Ok(())
   }
 
-  #[tracing::instrument(skip(args,sink_channel), level="debug", ret, err)]
-  pub async fn new(args: &SourceConfig, sink_channel: tokio::sync::mpsc::Sender<BytesMut>) -> Result<Self, IoError> {    
-    Self::check_permissions(&args.input_file).await?;
+  /// Opens the input file with the iflag flags, returns it with its inode and size.
+  pub async fn open_file(path: &PathBuf, args: &SourceConfig) -> Result<(tokio::fs::File, u64, usize), Failure> {
+    Self::check_permissions(path).await?;
 
-    let source = tokio::fs::File::open(&args.input_file).await.map_err(|e| IoError::InputFileOpenError(e.to_string()))?;
-    let metadata = tokio::fs::metadata(&args.input_file).await.map_err(|e| IoError::FileMetadataAcquireError(e.to_string()))?;
+    let source = tokio::fs::OpenOptions::new()
+      .read(true)
+      .custom_flags(device::open_flags(args.direct, args.dsync, args.sync))
+      .open(path)
+      .await
+      .map_err(|e| match e.kind() {
+        std::io::ErrorKind::PermissionDenied => IoError::InputFileNoReadPermission(path.display().to_string()),
+        _ if args.direct && e.raw_os_error() == Some(libc::EINVAL) => IoError::InputFileOpenError(format!("{}: direct I/O is not supported there", path.display())),
+        _ => IoError::InputFileOpenError(e.to_string()),
+      }.caused_by(&e))?;
+    let metadata = tokio::fs::metadata(path).await.map_err(|e| IoError::FileMetadataAcquireError(e.to_string()).caused_by(&e))?;
+    Ok((source, metadata.ino(), metadata.len() as usize))
+  }
 
-    let file_inode = metadata.ino();
-    let file_size = metadata.len() as usize;
-    let blake2b = Params::new().hash_length(64).to_state();
-    let sha_3_512 = sha3::Sha3_512::new();
-    let crc32 = crc32fast::Hasher::new();
+  #[tracing::instrument(skip(args,sink_channel), level="debug", ret, err)]
+  pub async fn new(args: &SourceConfig, sink_channel: tokio::sync::mpsc::Sender<Chunk>) -> Result<Self, Failure> {    
+    let skip = args.skip * args.block_size + args.resume_offset;
+    let mut direct = None;
+    let mut parallel = None;
+    let (source, file_inode, file_size, watch): (Box<dyn SourceDevice>, u64, usize, _) = match &args.input_file {
+      Some(path) => {
+        let (mut file, inode, size) = Self::open_file(path, args).await?;
+        if args.direct {
+          let block = device::logical_block_size(file.as_raw_fd()).map_err(|e| IoError::InputFileOpenError(e.to_string()).caused_by(&e))?;
+          if !args.block_size.is_multiple_of(block) || !skip.is_multiple_of(block) {
+            return Err(IoError::InputFileOpenError(format!("iflag=direct needs ibs and skip in multiples of the {} byte logical block size", block)).into());
+          }
+          let clone = file.try_clone().await.map_err(|e| IoError::InputFileOpenError(e.to_string()).caused_by(&e))?;
+          direct = Some(Arc::new(clone.into_std().await));
+        }
+        // procfs and sysfs files have data but no size, they are read to the end instead
+        let mut sized = false;
+        if args.readers > 1 {
+          sized = device::file_size(file.as_raw_fd()).is_ok_and(|size| size > 0);
+          // looking moved the file offset
+          file.rewind().await.map_err(|e| IoError::InputFileSeekError(e.to_string()).caused_by(&e))?;
+        }
+        if sized && !args.noerror && !args.sparse && device::is_positional(file.as_raw_fd()) {
+          parallel = match &direct {
+            Some(direct) => Some(direct.clone()),
+            None => {
+              let clone = file.try_clone().await.map_err(|e| IoError::InputFileOpenError(e.to_string()).caused_by(&e))?;
+              Some(Arc::new(clone.into_std().await))
+            },
+          };
+        }
+        (Box::new(file), inode, size, SourceWatch::new(path, args.on_change)?)
+      },
+      // the size of a stream is unknown
+      None => (Box::new(Stream(tokio::io::stdin())), 0, 0, None),
+    };
+    if args.readers > 1 && parallel.is_none() {
+      eprintln!("ruplica: warning: --readers needs an input file or block device with a size, without conv=noerror or conv=sparse, reading with one task");
+    }
+    let hashers = args.initial_hashers.clone().unwrap_or_else(|| Hashers::new(args.hash_selection()));
     let position = 0;
-    let estimated_size = file_size;
+    let mut estimated_size = file_size.saturating_sub(skip);
+    if let Some(count) = args.count {
+      estimated_size = estimated_size.min((count * args.block_size).saturating_sub(args.resume_offset));
+    }
 
-    let out = Self {
+    let mut out = Self {
       read_size: args.block_size,
-      source: Box::new(source),
-      blake2b,
-      sha_3_512,
-      crc32,
+      source,
+      hashers,
       inode: file_inode,
       file_size,
       position,
       estimated_size,
       sink_channel,
+      sent: 0,
+      queue_stalls: 0,
+      buffers: Arc::new(BufferPool::new()),
+      throttle: Arc::new(Throttle::default()),
+      noerror: args.noerror,
+      pad_blocks: args.pad_blocks,
+      // holes can only be found in files
+      sparse: args.sparse && args.input_file.is_some(),
+      data_extent: (0, 0),
+      fullblock: args.fullblock,
+      short_reads: 0,
+      warn_partial_read: args.count.is_some() && !args.fullblock,
+      partial_read: None,
+      nocache: args.nocache,
+      direct,
+      // a resumed copy only commits whole input blocks
+      remaining_blocks: args.count.map(|count| count.saturating_sub(args.resume_offset / args.block_size)),
+      watch,
+      readers: args.readers,
+      parallel,
     };
+    out.skip(skip).await?;
 
     Ok(out)
   }
 
+  /// Moves the input `length` bytes forward, reading and discarding when it cannot seek.
+  pub async fn skip(&mut self, length: usize) -> Result<(), Failure> {
+    if length == 0 {
+      return Ok(());
+    }
+    match self.source.seek(SeekFrom::Current(length as i64)).await {
+      Ok(_) => {
+        if self.file_size > 0 && self.position + length > self.file_size {
+          tracing::warn!("cannot skip to offset {}, input is only {} bytes", self.position + length, self.file_size);
+        }
+      },
+      Err(e) if device::is_unseekable(&e) => {
+        let mut scratch = vec![0u8; self.read_size.min(length)];
+        let mut remaining = length;
+        while remaining > 0 {
+          let take = remaining.min(scratch.len());
+          let read = self.source.read(&mut scratch[..take]).await.map_err(|e| Failure::read(&e, self.position + length - remaining))?;
+          if read == 0 {
+            tracing::warn!("cannot skip to offset {}, input ended early", self.position + length);
+            break;
+          }
+          remaining -= read;
+        }
+      },
+      Err(e) => return Err(IoError::InputFileSeekError(e.to_string()).caused_by(&e)),
+    }
+    self.position += length;
+    Ok(())
+  }
+
+  /// Reads the next block into `buf`. With fullblock short reads are repeated until the block
+  /// is full or the input ends.
+  pub async fn read_block(&mut self, buf: &mut BytesMut) -> std::io::Result<usize> {
+    let mut total = 0;
+    loop {
+      let read = match self.direct.clone() {
+        Some(file) => {
+          let mut block = std::mem::take(buf);
+          block.resize(self.read_size, 0);
+          let offset = (self.position + total) as u64;
+          let (block, read) = tokio::task::spawn_blocking(move || {
+            let read = file.read_at(&mut block[total..], offset);
+            (block, read)
+          })
+          .await?;
+          *buf = block;
+          match read {
+            Ok(read) => {
+              buf.truncate(total + read);
+              read
+            },
+            Err(e) => {
+              buf.truncate(total);
+              return Err(e);
+            }
+          }
+        },
+        // a reused buffer can have room for more than a block
+        None => self.source.read_buf(&mut buf.limit(self.read_size - total)).await?,
+      };
+      if read > 0 && total + read < self.read_size {
+        self.short_reads += 1;
+      }
+      total += read;
+      if read == 0 || !self.fullblock || total >= self.read_size {
+        break;
+      }
+    }
+    if total > 0 && self.warn_partial_read {
+      match self.partial_read.take() {
+        Some(bytes) => {
+          eprintln!("ruplica: warning: partial read ({} bytes); suggest iflag=fullblock", bytes);
+          self.warn_partial_read = false;
+        },
+        None if total < self.read_size => self.partial_read = Some(total),
+        None => {},
+      }
+    }
+    if self.nocache && total > 0 {
+      device::drop_cache(self.source.as_raw_fd(), self.position as u64, total as u64);
+    }
+    Ok(total)
+  }
+
+  /// Moves over the next block when it lies entirely in a hole of the input and returns its length,
+  /// the caller sends zeros for it. Gives up on holes if the input cannot tell where they are.
+  pub async fn skip_hole(&mut self) -> Option<usize> {
+    if !self.sparse || self.position >= self.file_size {
+      return None;
+    }
+    let position = self.position as u64;
+    if position >= self.data_extent.1 {
+      let found = device::next_data(self.source.as_raw_fd(), position);
+      // looking moved the file offset
+      let restored = self.source.seek(SeekFrom::Start(position)).await;
+      match (found, restored) {
+        (Ok(extent), Ok(_)) => self.data_extent = extent.unwrap_or((u64::MAX, u64::MAX)),
+        (Err(e), _) | (_, Err(e)) => {
+          tracing::debug!("Cannot find holes in the input ({}), reading all of it", e);
+          self.sparse = false;
+          return None;
+        }
+      }
+    }
+    let length = self.read_size.min(self.file_size - self.position);
+    if position + length as u64 > self.data_extent.0 {
+      return None;
+    }
+    match self.source.seek(SeekFrom::Start(position + length as u64)).await {
+      Ok(_) => Some(length),
+      Err(e) => {
+        tracing::debug!("Cannot seek over a hole in the input ({}), reading all of it", e);
+        self.sparse = false;
+        None
+      }
+    }
+  }
+
+  /// Re-stats the input, records a change in `changed` and stops the copy if it should.
+  pub async fn check_source(&mut self, changed: &Mutex<Option<String>>) -> Result<(), Failure> {
+    let Some(watch) = self.watch.as_mut() else {
+      return Ok(());
+    };
+    let Some(change) = watch.check() else {
+      return Ok(());
+    };
+    changed.lock().await.get_or_insert(change.clone());
+    match watch.failure(&change, self.position) {
+      Some(failure) => Err(failure),
+      None => Ok(()),
+    }
+  }
+
+  /// Sends `chunk` on to the sink, false when the sink stopped. Waiting for room in the queue
+  /// counts as a stall.
+  pub async fn send_chunk(&mut self, chunk: Chunk) -> bool {
+    self.buffers.hold(chunk.data.len());
+    let chunk = match self.sink_channel.try_send(chunk) {
+      Ok(()) => return true,
+      Err(TrySendError::Full(chunk)) => chunk,
+      Err(TrySendError::Closed(_)) => return false,
+    };
+    self.queue_stalls += 1;
+    self.sink_channel.send(chunk).await.is_ok()
+  }
+
+  /// Copies the queue and memory figures into the statistics.
+  pub fn update_buffer_statistics(&self, statistics: &mut ReadStatistics) {
+    statistics.queue_stalls = self.queue_stalls;
+    statistics.peak_memory = self.buffers.peak();
+  }
+
+  /// Reads the rest of the input with `readers` tasks, reader i taking blocks i, i + readers, ...
+  /// with pread, and sends the blocks on as they come in. No reader gets more than two blocks
+  /// per reader ahead of the first unfinished one, which bounds what waits to be put back in
+  /// order here for the hashes and in the sink.
+  pub async fn read_parallel(&mut self, file: Arc<std::fs::File>, task: &Mutex<Task>, statistics: &Mutex<ReadStatistics>, source_changed: &Mutex<Option<String>>) -> Result<(), Failure> {
+    let start = self.position as u64;
+    // stat reports 0 for block devices
+    let size = device::file_size(file.as_raw_fd()).map_err(|e| Failure::read(&e, self.position))?;
+    let mut length = size.saturating_sub(start);
+    if let Some(remaining) = self.remaining_blocks {
+      length = length.min((remaining * self.read_size) as u64);
+    }
+    statistics.lock().await.estimated_bytes = length;
+    let block_size = self.read_size as u64;
+    let blocks = length.div_ceil(block_size);
+    let window = 2 * self.readers as u64;
+
+    // the first block not yet received, readers wait for it to move
+    let (finished_below, _) = tokio::sync::watch::channel(0u64);
+    let (ready, mut received) = tokio::sync::mpsc::channel::<Chunk>(window as usize);
+    let mut readers = tokio::task::JoinSet::new();
+    for first in 0..self.readers as u64 {
+      let (file, ready, mut finished) = (file.clone(), ready.clone(), finished_below.subscribe());
+      let (stride, direct, nocache, buffers, throttle) = (self.readers, self.direct.is_some(), self.nocache, self.buffers.clone(), self.throttle.clone());
+      readers.spawn(async move {
+        for block in (first..blocks).step_by(stride) {
+          if finished.wait_for(|below| block < below + window).await.is_err() {
+            break;
+          }
+          let offset = block * block_size;
+          let wanted = (length - offset).min(block_size) as usize;
+          throttle.acquire(wanted).await;
+          let reading = file.clone();
+          let buffer = buffers.take(block_size as usize, direct.then_some(DIRECT_ALIGNMENT));
+          let data = tokio::task::spawn_blocking(move || read_at_full(&reading, buffer, start + offset, block_size as usize, wanted, direct))
+            .await
+            .map_err(|e| Failure::from(IoError::ReadError(format!("reader failed: {}", e))))?
+            .map_err(|e| Failure::read(&e, (start + offset) as usize))?;
+          if nocache {
+            device::drop_cache(file.as_raw_fd(), start + offset, wanted as u64);
+          }
+          if ready.send(Chunk::new(data, offset)).await.is_err() {
+            break;
+          }
+        }
+        Ok::<(), Failure>(())
+      });
+    }
+    drop(ready);
+
+    let mut done = BTreeSet::new();
+    let mut below = 0;
+    // only the hashes need the blocks in order, the sink puts them back in order itself if it has to
+    let mut reorder = self.hashers.selection.any().then(|| Reorder::new(0));
+    let outcome = loop {
+      tokio::select! {
+        Some(chunk) = received.recv() => {
+          let bytes = chunk.data.len();
+          {
+            let mut statistics = statistics.lock().await;
+            statistics.add_read(bytes as u64, bytes == self.read_size);
+            self.update_buffer_statistics(&mut statistics);
+          }
+          done.insert(chunk.offset / block_size);
+          while done.remove(&below) {
+            below += 1;
+          }
+          finished_below.send_replace(below);
+          let chunks = match reorder.as_mut() {
+            Some(reorder) => reorder.push(chunk),
+            None => vec![chunk],
+          };
+          let mut sent = Ok(());
+          for mut chunk in chunks {
+            // only the last block can be short
+            if self.pad_blocks && chunk.data.len() < self.read_size {
+              chunk.data.resize(self.read_size, 0);
+            }
+            self.hashers.update(&chunk.data);
+            self.sent = self.sent.max(chunk.offset + chunk.length());
+            if !self.send_chunk(chunk).await {
+              sent = Err(Failure::from(IoError::ChannelEror("sink stopped accepting data".to_string())));
+              break;
+            }
+          }
+          if let Err(e) = sent {
+            break Err(e);
+          }
+          task.lock().await.ping();
+          if self.watch.as_ref().is_some_and(|watch| watch.due()) {
+            if let Err(e) = self.check_source(source_changed).await {
+              break Err(e);
+            }
+          }
+        },
+        Some(joined) = readers.join_next() => match joined {
+          Ok(Ok(())) => {},
+          Ok(Err(e)) => break Err(e),
+          Err(e) => break Err(Failure::from(IoError::ReadError(format!("reader failed: {}", e)))),
+        },
+        else => break Ok(()),
+      }
+    };
+    readers.abort_all();
+    self.position += (below * block_size).min(length) as usize;
+    outcome
+  }
+
   #[tracing::instrument(skip(sink_channel, config, dd_context), level="debug", ret, err)]
-  pub async fn run(sink_channel: tokio::sync::mpsc::Sender<BytesMut>, config: SourceConfig, dd_context: Arc<Mutex<DdContext>>) -> Result<(), IoError> {
+  pub async fn run(sink_channel: tokio::sync::mpsc::Sender<Chunk>, config: SourceConfig, dd_context: Arc<Mutex<DdContext>>) -> Result<(), Failure> {
     tracing::info!("Preparing reader");  
     let mut sink = DataSource::new(&config, sink_channel).await?;
+    {
+      let context = dd_context.lock().await;
+      sink.buffers = context.buffers.clone();
+      sink.throttle = context.throttle.clone();
+    }
 
     let task = {
       dd_context.lock().await.new_task("DataSource").await
@@ -89,49 +461,158 @@ impl DataSource {
     tracing::debug!("Spawning source thread");
     tokio::spawn(async move {
       task.lock().await.change_state(crate::environment::statistics::TaskStatus::Running);
-      let mut statistics = statistics.lock().await;
-      let notifications = dd_context.lock().await.notifications.clone();
-      statistics.init();
+      let notifications = dd_context.lock().await.main_notifications.clone();
+      let digests = dd_context.lock().await.source_digests.clone();
+      let failure = dd_context.lock().await.failure.clone();
+      let source_changed = dd_context.lock().await.source_changed.clone();
+      {
+        // the lock is only taken per update so the progress reporter can read along
+        let mut statistics = statistics.lock().await;
+        statistics.init();
+        statistics.estimated_bytes = sink.estimated_size as u64;
+      }
   
       tracing::debug!("Reporting readiness");
       tracing::debug!("Started reading data");
-      loop {
-        let mut buf = BytesMut::with_capacity(sink.read_size);
-        task.lock().await.ping();
-        match sink.source.read_buf(&mut buf).await {
-          Ok(bytes) => {
-            tracing::debug!("read {} bytes", bytes);
-            match bytes {
-              0 => {
-                assert!(buf.is_empty());
-                sink.sink_channel.send(buf).await.unwrap(); // sebd empty buffer.
-                task.lock().await.complete(0);
-                notifications.notify_waiters();
-                sink.sink_channel.closed().await; // we wait for the sink to finish.
-                break;
+      let mut outcome: Result<(), Failure> = match sink.parallel.clone() {
+        Some(file) => sink.read_parallel(file, &task, &statistics, &source_changed).await,
+        None => loop {
+          let mut buf = sink.buffers.take(sink.read_size, sink.direct.as_ref().map(|_| DIRECT_ALIGNMENT));
+          task.lock().await.ping();
+          let mut hole = false;
+          let read = match sink.remaining_blocks {
+            Some(0) => Ok(0),
+            _ => match sink.skip_hole().await {
+              Some(length) => {
+                hole = true;
+                buf.resize(length, 0);
+                Ok(length)
               },
-              _ => {
-                statistics.add_read(bytes.try_into().unwrap());
-                task.lock().await.ping();
-                sink.sink_channel.send(buf).await.unwrap();
+              None => sink.read_block(&mut buf).await,
+            },
+          };
+          match read {
+            Ok(bytes) => {
+              tracing::debug!("read {} bytes", bytes);
+              match bytes {
+                0 => {
+                  assert!(buf.is_empty());
+                  break Ok(());
+                },
+                _ => {
+                  {
+                    let mut statistics = statistics.lock().await;
+                    statistics.add_read(bytes.try_into().unwrap(), bytes == sink.read_size);
+                    statistics.short_reads = sink.short_reads;
+                    sink.update_buffer_statistics(&mut statistics);
+                    if hole {
+                      statistics.hole_bytes += bytes as u64;
+                    }
+                  }
+                  sink.position += bytes;
+                  if let Some(remaining) = sink.remaining_blocks.as_mut() {
+                    *remaining -= 1;
+                  }
+                  if sink.pad_blocks && bytes < sink.read_size {
+                    buf.resize(sink.read_size, 0);
+                  }
+                  sink.hashers.update(&buf);
+                  task.lock().await.ping();
+                  // holes were not read, they cost nothing
+                  if !hole {
+                    sink.throttle.acquire(bytes).await;
+                  }
+                  let chunk = Chunk::new(buf, sink.sent);
+                  sink.sent += chunk.length();
+                  if !sink.send_chunk(chunk).await {
+                    break Err(Failure::from(IoError::ChannelEror("sink stopped accepting data".to_string())));
+                  }
+                  if sink.watch.as_ref().is_some_and(|watch| watch.due()) {
+                    if let Err(e) = sink.check_source(&source_changed).await {
+                      break Err(e);
+                    }
+                  }
+                }
+              }
+            },
+            Err(e) if sink.noerror => {
+              tracing::error!("Error reading data at offset {}: {}", sink.position, e);
+              statistics.lock().await.add_error();
+              if let Some(remaining) = sink.remaining_blocks.as_mut() {
+                *remaining -= 1;
+              }
+              // step over the unreadable block, a stream has already moved past it
+              let next = sink.position + sink.read_size;
+              if sink.source.seek(SeekFrom::Start(next as u64)).await.is_ok() {
+                sink.position = next;
               }
+              if sink.pad_blocks {
+                let zeros = BytesMut::zeroed(sink.read_size);
+                sink.hashers.update(&zeros);
+                let chunk = Chunk::new(zeros, sink.sent);
+                sink.sent += chunk.length();
+                if !sink.send_chunk(chunk).await {
+                  break Err(Failure::from(IoError::ChannelEror("sink stopped accepting data".to_string())));
+                }
+              }
+            },
+            Err(e) => {
+              statistics.lock().await.add_error();
+              break Err(Failure::read(&e, sink.position));
             }
-          },
-          Err(e) => {
-            tracing::error!("Error reading data: {}", e);
-            task.lock().await.fail(-2);
-            statistics.add_error();
-            notifications.notify_waiters();
-            break;
           }
+        },
+      };
+      if outcome.is_ok() {
+        outcome = sink.check_source(&source_changed).await;
+      }
+      if outcome.is_ok() {
+        *digests.lock().await = sink.hashers.digests();
+        // an empty chunk tells the sink we are done
+        if sink.sink_channel.send(Chunk::end(sink.sent)).await.is_err() {
+          outcome = Err(Failure::from(IoError::ChannelEror("sink stopped before the end of input".to_string())));
+        }
+      }
+      {
+        let mut statistics = statistics.lock().await;
+        sink.update_buffer_statistics(&mut statistics);
+      }
+      match outcome {
+        Ok(()) => {
+          task.lock().await.complete(0);
+          notifications.notify_one();
+          sink.sink_channel.closed().await; // we wait for the sink to finish.
+        },
+        Err(e) => {
+          tracing::error!("Reading failed: {}", e);
+          task.lock().await.fail(-2, e.to_string());
+          DdContext::record_failure(&failure, e).await;
+          // dropping the sender lets the sink flush what it got and stop
+          drop(sink);
+          notifications.notify_one();
         }
-        
       }
     });
     Ok(())
   }
 }
 
+/// Reads `wanted` bytes at `offset`, erring when the input ends first. Direct I/O reads whole
+/// blocks, the kernel stops at the end of the file.
+fn read_at_full(file: &std::fs::File, mut data: BytesMut, offset: u64, block_size: usize, wanted: usize, direct: bool) -> std::io::Result<BytesMut> {
+  data.resize(if direct { block_size } else { wanted }, 0);
+  let mut total = 0;
+  while total < wanted {
+    match file.read_at(&mut data[total..], offset + total as u64) {
+      Ok(0) => return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "input shrank while it was read")),
+      Ok(read) => total += read,
+      Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {},
+      Err(e) => return Err(e),
+    }
+  }
+  data.truncate(wanted);
+  Ok(data)
+}
 
 #[cfg(test)]
 mod tests {
@@ -139,9 +620,10 @@ mod tests {
   #[tokio::test]
   async fn test_data_source_new() {
     let source_config = SourceConfig {
-      input_file: PathBuf::from("Cargo.toml"),
+      input_file: Some(PathBuf::from("Cargo.toml")),
       buffer_size: 512,
       block_size: 512,
+      ..SourceConfig::default()
     };
 
     let (sender,_receiver) = tokio::sync::mpsc::channel(1);
@@ -152,12 +634,79 @@ mod tests {
     assert_ne!(source.inode, 0);
   }
 
+  #[tokio::test]
+  async fn test_fullblock_joins_short_reads() {
+    let dir = tempfile::tempdir().unwrap();
+    let fifo = dir.path().join("fifo");
+    let path = std::ffi::CString::new(fifo.to_str().unwrap()).unwrap();
+    assert_eq!(unsafe { libc::mkfifo(path.as_ptr(), 0o600) }, 0);
+    let writer = {
+      let fifo = fifo.clone();
+      std::thread::spawn(move || {
+        use std::io::Write;
+        let mut pipe = std::fs::OpenOptions::new().write(true).open(fifo).unwrap();
+        for _ in 0..5 {
+          pipe.write_all(&[1u8; 1000]).unwrap();
+          pipe.flush().unwrap();
+          std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+      })
+    };
+    let source_config = SourceConfig { input_file: Some(fifo), block_size: 4096, fullblock: true, count: Some(1), ..SourceConfig::default() };
+    let (sender, _receiver) = tokio::sync::mpsc::channel(1);
+    let mut source = DataSource::new(&source_config, sender).await.unwrap();
+    let mut buf = BytesMut::with_capacity(4096);
+    assert_eq!(source.read_block(&mut buf).await.unwrap(), 4096);
+    assert!(source.short_reads > 0);
+    let mut buf = BytesMut::with_capacity(4096);
+    assert_eq!(source.read_block(&mut buf).await.unwrap(), 904);
+    writer.join().unwrap();
+  }
+
+  #[tokio::test]
+  async fn test_fifo_copy_is_not_a_source_change() {
+    let dir = tempfile::tempdir().unwrap();
+    let (fifo, output) = (dir.path().join("fifo"), dir.path().join("output"));
+    let path = std::ffi::CString::new(fifo.to_str().unwrap()).unwrap();
+    assert_eq!(unsafe { libc::mkfifo(path.as_ptr(), 0o600) }, 0);
+    let writer = {
+      let fifo = fifo.clone();
+      std::thread::spawn(move || {
+        use std::io::Write;
+        let mut pipe = std::fs::OpenOptions::new().write(true).open(fifo).unwrap();
+        // every write moves the pipe's mtime and ctime
+        for i in 0..4u8 {
+          pipe.write_all(&[i; 3000]).unwrap();
+          std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+      })
+    };
+    let source_config = SourceConfig { input_file: Some(fifo), block_size: 4096, fullblock: true, on_change: crate::config::SourceChange::Fail, ..SourceConfig::default() };
+    let sink_config = crate::io::sink::config::SinkConfig { output_file: Some(output.clone()), block_size: 4096, ..Default::default() };
+    let context = Arc::new(Mutex::new(DdContext::new()));
+    let notifications = context.lock().await.main_notifications.clone();
+    let (sender, receiver) = tokio::sync::mpsc::channel(4);
+    DataSource::run(sender, source_config, context.clone()).await.unwrap();
+    crate::io::sink::core::DataSink::run(receiver, sink_config, context.clone()).await.unwrap();
+    while context.lock().await.are_tasks_pending().await {
+      notifications.notified().await;
+    }
+    writer.join().unwrap();
+
+    let context = context.lock().await;
+    assert!(context.failure.lock().await.is_none());
+    assert!(context.source_changed.lock().await.is_none());
+    let expected: Vec<u8> = (0..4u8).flat_map(|i| [i; 3000]).collect();
+    assert_eq!(std::fs::read(&output).unwrap(), expected);
+  }
+
   #[tokio::test]
   async fn test_data_source_check_permissions() {
     let source_config = SourceConfig {
-      input_file: PathBuf::from("Cargo.toml"),
+      input_file: Some(PathBuf::from("Cargo.toml")),
       buffer_size: 512,
       block_size: 512,
+      ..SourceConfig::default()
     };
 
     let (sender,_receiver) = tokio::sync::mpsc::channel(1);
@@ -169,4 +718,60 @@ mod tests {
     assert!(source.file_size == source.estimated_size);
     assert!(source.inode > 0);
   }
+
+  #[tokio::test]
+  async fn test_missing_input_reports_errno() {
+    let source_config = SourceConfig { input_file: Some(PathBuf::from("/nonexistent")), ..SourceConfig::default() };
+    let (sender, _receiver) = tokio::sync::mpsc::channel(1);
+    let error = crate::io::error::DdError::from(DataSource::new(&source_config, sender).await.unwrap_err());
+    let json: serde_json::Value = serde_json::from_str(&error.to_json()).unwrap();
+    assert_eq!(json["kind"], "source_open");
+    assert_eq!(json["exit_code"], 3);
+    assert_eq!(json["errno"], libc::ENOENT);
+  }
+
+  #[tokio::test]
+  async fn test_data_source_skip() {
+    let file = tempfile::NamedTempFile::new().unwrap();
+    std::fs::write(file.path(), b"0123456789abcdef").unwrap();
+    let source_config = SourceConfig {
+      input_file: Some(file.path().to_path_buf()),
+      block_size: 4,
+      skip: 2,
+      ..SourceConfig::default()
+    };
+
+    let (sender, _receiver) = tokio::sync::mpsc::channel(1);
+    let mut source = DataSource::new(&source_config, sender).await.unwrap();
+    assert_eq!(source.position, 8);
+    assert_eq!(source.estimated_size, 8);
+    let mut rest = Vec::new();
+    source.source.read_to_end(&mut rest).await.unwrap();
+    assert_eq!(rest, b"89abcdef");
+  }
+
+  #[tokio::test]
+  async fn test_data_source_count() {
+    let file = tempfile::NamedTempFile::new().unwrap();
+    std::fs::write(file.path(), b"0123456789abcdef").unwrap();
+    let source_config = SourceConfig {
+      input_file: Some(file.path().to_path_buf()),
+      block_size: 4,
+      skip: 1,
+      count: Some(2),
+      ..SourceConfig::default()
+    };
+
+    let (sender, mut receiver) = tokio::sync::mpsc::channel(10);
+    DataSource::run(sender, source_config, Arc::new(Mutex::new(DdContext::new()))).await.unwrap();
+    let mut copied = Vec::new();
+    while let Some(chunk) = receiver.recv().await {
+      if chunk.is_end() {
+        break;
+      }
+      assert_eq!(chunk.offset, copied.len() as u64);
+      copied.extend_from_slice(&chunk.data);
+    }
+    assert_eq!(copied, b"456789ab");
+  }
 }
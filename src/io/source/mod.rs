@@ -1,2 +1,3 @@
 pub mod core;
-pub mod config;
\ No newline at end of file
+pub mod config;
+pub mod snapshot;
\ No newline at end of file
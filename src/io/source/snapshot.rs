@@ -0,0 +1,133 @@
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::config::SourceChange;
+use crate::io::error::{Failure, IoError};
+
+/// How often the source re-stats its input while reading, it always does at the end.
+pub const SOURCE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// What `stat` says about the input, compared over the copy to notice it being modified.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceSnapshot {
+  pub inode: u64,
+  pub size: u64,
+  pub mtime: (i64, i64),
+  pub ctime: (i64, i64),
+  // the times of a block device node change without its data doing so, only a regular file's count
+  pub regular: bool,
+}
+
+impl SourceSnapshot {
+  pub fn take(path: &Path) -> Result<Self, Failure> {
+    let metadata = std::fs::metadata(path).map_err(|e| IoError::FileMetadataAcquireError(format!("{}: {}", path.display(), e)).caused_by(&e))?;
+    Ok(SourceSnapshot {
+      inode: metadata.ino(),
+      size: metadata.len(),
+      mtime: (metadata.mtime(), metadata.mtime_nsec()),
+      ctime: (metadata.ctime(), metadata.ctime_nsec()),
+      regular: metadata.is_file(),
+    })
+  }
+
+  /// Describes what differs in `now`, empty when nothing does.
+  pub fn changes(&self, now: &SourceSnapshot) -> Vec<String> {
+    let mut changes = Vec::new();
+    if self.inode != now.inode {
+      changes.push(format!("inode {} -> {}", self.inode, now.inode));
+    }
+    if self.size != now.size {
+      changes.push(format!("size {} -> {}", self.size, now.size));
+    }
+    if !self.regular || !now.regular {
+      return changes;
+    }
+    if self.mtime != now.mtime {
+      changes.push("mtime changed".to_string());
+    }
+    if self.ctime != now.ctime {
+      changes.push("ctime changed".to_string());
+    }
+    changes
+  }
+}
+
+/// Re-stats a named input while it is copied and decides what a change means for the copy.
+#[derive(Debug)]
+pub struct SourceWatch {
+  path: PathBuf,
+  // how the input was when it was opened, dropped once a change was warned about
+  snapshot: Option<SourceSnapshot>,
+  on_change: SourceChange,
+  last_check: Instant,
+}
+
+impl SourceWatch {
+  /// Watches regular files and block devices, None for FIFOs, sockets and character devices:
+  /// what they hold is gone once read, and their times change with every write to them.
+  pub fn new(path: &Path, on_change: SourceChange) -> Result<Option<Self>, Failure> {
+    let file_type = std::fs::metadata(path).map_err(|e| IoError::FileMetadataAcquireError(format!("{}: {}", path.display(), e)).caused_by(&e))?.file_type();
+    if !file_type.is_file() && !file_type.is_block_device() {
+      return Ok(None);
+    }
+    Ok(Some(SourceWatch { path: path.to_path_buf(), snapshot: Some(SourceSnapshot::take(path)?), on_change, last_check: Instant::now() }))
+  }
+
+  pub fn due(&self) -> bool {
+    self.last_check.elapsed() >= SOURCE_CHECK_INTERVAL
+  }
+
+  /// Re-stats the input and describes what changed since it was opened, None when nothing did.
+  pub fn check(&mut self) -> Option<String> {
+    self.last_check = Instant::now();
+    let snapshot = self.snapshot.as_ref()?;
+    let changes = match SourceSnapshot::take(&self.path) {
+      Ok(now) => snapshot.changes(&now),
+      // the input was removed or renamed away
+      Err(e) => vec![e.to_string()],
+    };
+    if changes.is_empty() {
+      return None;
+    }
+    let description = format!("{} ({})", self.path.display(), changes.join(", "));
+    tracing::warn!("source changed while reading: {}", description);
+    if self.on_change == SourceChange::Warn {
+      // report it once, not at every check
+      self.snapshot = None;
+    }
+    Some(description)
+  }
+
+  /// The error that stops the copy at `position` because of `change`, None when it only gets a warning.
+  pub fn failure(&self, change: &str, position: usize) -> Option<Failure> {
+    match self.on_change {
+      SourceChange::Warn => None,
+      SourceChange::Fail | SourceChange::Retry => Some(Failure {
+        error: IoError::SourceChanged(change.to_string()),
+        offset: Some(position as u64),
+        errno: None,
+      }),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_snapshot_notices_modification() {
+    let file = tempfile::NamedTempFile::new().unwrap();
+    std::fs::write(file.path(), b"before").unwrap();
+    let before = SourceSnapshot::take(file.path()).unwrap();
+    assert!(before.changes(&SourceSnapshot::take(file.path()).unwrap()).is_empty());
+
+    std::fs::write(file.path(), b"after, and longer").unwrap();
+    let times = std::fs::FileTimes::new().set_modified(std::time::SystemTime::UNIX_EPOCH);
+    std::fs::File::options().write(true).open(file.path()).unwrap().set_times(times).unwrap();
+    let changes = before.changes(&SourceSnapshot::take(file.path()).unwrap());
+    assert!(changes.contains(&"size 6 -> 17".to_string()), "{:?}", changes);
+    assert!(changes.contains(&"mtime changed".to_string()), "{:?}", changes);
+  }
+}
@@ -0,0 +1,148 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+/// Limits on how fast the input is read, in bytes and in reads per second.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+  pub rate: Option<u64>,
+  pub iops: Option<u64>,
+}
+
+impl Limits {
+  pub fn is_limited(&self) -> bool {
+    self.rate.is_some() || self.iops.is_some()
+  }
+}
+
+/// Token buckets for bytes and reads, refilled at the limits and holding at most one second
+/// worth. A read may take more than is in a bucket, the next one then waits until the debt is
+/// paid off, so blocks larger than a second of the rate still come out at the rate on average.
+#[derive(Debug)]
+struct Bucket {
+  limits: Limits,
+  bytes: f64,
+  ops: f64,
+  last: Instant,
+}
+
+impl Bucket {
+  fn refill(&mut self, now: Instant) {
+    let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+    self.last = now;
+    if let Some(rate) = self.limits.rate {
+      self.bytes = (self.bytes + elapsed * rate as f64).min(rate as f64);
+    }
+    if let Some(iops) = self.limits.iops {
+      self.ops = (self.ops + elapsed * iops as f64).min(iops as f64);
+    }
+  }
+
+  /// How long until both buckets are out of debt.
+  fn wait(&self) -> Duration {
+    let owed = |tokens: f64, limit: Option<u64>| match limit {
+      Some(limit) if tokens < 0.0 => -tokens / limit as f64,
+      _ => 0.0,
+    };
+    Duration::from_secs_f64(owed(self.bytes, self.limits.rate).max(owed(self.ops, self.limits.iops)))
+  }
+}
+
+/// Paces the reads of a copy, shared by every task reading the input. The limits can be changed
+/// while the copy runs, readers waiting on the old ones then wait for the new ones instead.
+#[derive(Debug)]
+pub struct Throttle {
+  bucket: Mutex<Bucket>,
+  changed: Notify,
+}
+
+impl Default for Throttle {
+  fn default() -> Self {
+    Throttle::new(Limits::default())
+  }
+}
+
+impl Throttle {
+  pub fn new(limits: Limits) -> Self {
+    let bucket = Bucket { limits, bytes: 0.0, ops: 0.0, last: Instant::now() };
+    Throttle { bucket: Mutex::new(bucket), changed: Notify::new() }
+  }
+
+  pub fn limits(&self) -> Limits {
+    self.bucket.lock().unwrap().limits
+  }
+
+  pub fn set_limits(&self, limits: Limits) {
+    {
+      let mut bucket = self.bucket.lock().unwrap();
+      bucket.refill(Instant::now());
+      bucket.limits = limits;
+      // no debt is carried over to a lifted limit, nor more than a second of the new one
+      bucket.bytes = limits.rate.map_or(0.0, |rate| bucket.bytes.min(rate as f64));
+      bucket.ops = limits.iops.map_or(0.0, |iops| bucket.ops.min(iops as f64));
+    }
+    self.changed.notify_waiters();
+  }
+
+  /// Takes one read of `bytes` from the buckets and waits until the limits allow it.
+  pub async fn acquire(&self, bytes: usize) {
+    {
+      let mut bucket = self.bucket.lock().unwrap();
+      if !bucket.limits.is_limited() {
+        return;
+      }
+      bucket.refill(Instant::now());
+      if bucket.limits.rate.is_some() {
+        bucket.bytes -= bytes as f64;
+      }
+      if bucket.limits.iops.is_some() {
+        bucket.ops -= 1.0;
+      }
+    }
+    loop {
+      let changed = self.changed.notified();
+      let wait = {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill(Instant::now());
+        bucket.wait()
+      };
+      if wait.is_zero() {
+        return;
+      }
+      tokio::select! {
+        _ = tokio::time::sleep(wait) => {},
+        _ = changed => {},
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn test_throttle_paces_reads() {
+    let throttle = Throttle::new(Limits { rate: Some(100_000), iops: None });
+    let started = Instant::now();
+    for _ in 0..4 {
+      throttle.acquire(5000).await;
+    }
+    // the bucket starts out empty
+    assert!(started.elapsed() >= Duration::from_millis(190));
+
+    throttle.set_limits(Limits { rate: None, iops: Some(50) });
+    let started = Instant::now();
+    for _ in 0..5 {
+      throttle.acquire(1 << 20).await;
+    }
+    assert!(started.elapsed() >= Duration::from_millis(90));
+
+    throttle.set_limits(Limits::default());
+    let started = Instant::now();
+    throttle.acquire(1 << 30).await;
+    assert!(started.elapsed() < Duration::from_millis(10));
+  }
+}
@@ -0,0 +1,425 @@
+use std::os::fd::AsRawFd;
+use std::sync::Arc;
+
+use io_uring::{opcode, squeue, types, IoUring};
+use tokio::sync::Mutex;
+
+use crate::environment::statistics::{DdContext, ReadStatistics, Task, TaskStatus, WriteStatistics};
+use crate::io::aligned::{AlignedBuffer, DIRECT_ALIGNMENT};
+use crate::io::device;
+use crate::io::error::{Failure, IoError};
+use crate::io::sink::config::SinkConfig;
+use crate::io::sink::core::DataSink;
+use crate::io::source::config::SourceConfig;
+use crate::io::source::core::DataSource;
+use crate::io::source::snapshot::SourceWatch;
+use crate::io::zerocopy;
+
+/// Most reads and writes kept in flight, each owns one block buffer.
+pub const QUEUE_DEPTH: usize = 32;
+/// Most memory the block buffers may take together, large blocks get fewer of them.
+const BUFFER_MEMORY: usize = 256 << 20;
+// set in the user_data of writes, the rest is the buffer index
+const WRITE: u64 = 1 << 32;
+
+/// Both ends are files or block devices and no block needs to be looked at on the way.
+pub fn eligible(source: &SourceConfig, sink: &SinkConfig) -> bool {
+  zerocopy::passthrough(source, sink) && source.input_file.is_some() && sink.output_file.is_some()
+}
+
+/// The part of the copy a buffer carries.
+#[derive(Debug, Clone, Copy)]
+struct Slot {
+  // from the start of the copy
+  offset: u64,
+  // bytes read into the buffer so far
+  length: usize,
+  // bytes of them written so far
+  written: usize,
+}
+
+/// Copies file to file with up to `QUEUE_DEPTH` block reads and writes in flight. Blocks complete
+/// in any order and are written at the offset they were read from, which is why nothing may need
+/// to see them in order.
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+pub struct UringCopy {
+  #[derivative(Debug="ignore")]
+  ring: IoUring,
+  #[derivative(Debug="ignore")]
+  input: std::fs::File,
+  #[derivative(Debug="ignore")]
+  output: std::fs::File,
+  input_offset: u64,
+  output_offset: u64,
+  // bytes to copy, shrinks when the input turns out shorter
+  length: u64,
+  block_size: usize,
+  // with iflag=direct the rest of a short read that is not aligned is read through the page cache
+  direct_input: bool,
+  // with oflag=direct a tail that is not a multiple of this is written through the page cache
+  direct_output: Option<usize>,
+  #[derivative(Debug="ignore")]
+  buffers: Vec<AlignedBuffer>,
+  // the buffers are registered with the ring and used by ReadFixed and WriteFixed
+  fixed: bool,
+  fsync: bool,
+  fdatasync: bool,
+  watch: Option<SourceWatch>,
+  #[derivative(Debug="ignore")]
+  read_statistics: Arc<Mutex<ReadStatistics>>,
+  #[derivative(Debug="ignore")]
+  write_statistics: Arc<Mutex<WriteStatistics>>,
+  #[derivative(Debug="ignore")]
+  source_changed: Arc<Mutex<Option<String>>>,
+}
+
+impl UringCopy {
+  /// Sets up the ring and opens both ends, None when the kernel has no io_uring for us or an
+  /// end is not a file or block device.
+  pub async fn open(source: &SourceConfig, sink: &SinkConfig, dd_context: &DdContext) -> Result<Option<Self>, Failure> {
+    let (Some(input_path), Some(output_path)) = (&source.input_file, &sink.output_file) else {
+      return Ok(None);
+    };
+    if source.block_size > u32::MAX as usize {
+      return Ok(None);
+    }
+    // old kernels, seccomp filters and containers refuse it
+    let ring = match IoUring::new(2 * QUEUE_DEPTH as u32) {
+      Ok(ring) => ring,
+      Err(e) => {
+        eprintln!("ruplica: warning: io_uring is not available ({}), using the tokio engine", e);
+        return Ok(None);
+      }
+    };
+    let skip = (source.skip * source.block_size) as u64;
+    let seek = (sink.seek * sink.block_size) as u64;
+
+    let input = DataSource::open_file(input_path, source).await?.0.into_std().await;
+    if !device::is_positional(input.as_raw_fd()) {
+      eprintln!("ruplica: warning: --engine uring needs files or block devices, using the tokio engine");
+      return Ok(None);
+    }
+    if source.direct {
+      let block = device::logical_block_size(input.as_raw_fd()).map_err(|e| IoError::InputFileOpenError(e.to_string()).caused_by(&e))?;
+      if !source.block_size.is_multiple_of(block) || !skip.is_multiple_of(block as u64) {
+        return Err(IoError::InputFileOpenError(format!("iflag=direct needs ibs and skip in multiples of the {} byte logical block size", block)).into());
+      }
+    }
+    let output = DataSink::open_file(output_path, sink, seek).await?.0.into_std().await;
+    if !device::is_positional(output.as_raw_fd()) {
+      eprintln!("ruplica: warning: --engine uring needs files or block devices, using the tokio engine");
+      return Ok(None);
+    }
+    let mut direct_output = None;
+    if sink.direct {
+      let block = device::logical_block_size(output.as_raw_fd()).map_err(|e| IoError::OutputFileOpenError(e.to_string()).caused_by(&e))?;
+      if !sink.block_size.is_multiple_of(block) || !seek.is_multiple_of(block as u64) {
+        return Err(IoError::OutputFileOpenError(format!("oflag=direct needs obs and seek in multiples of the {} byte logical block size", block)).into());
+      }
+      direct_output = Some(block);
+    }
+
+    // block devices report their size through lseek, not stat
+    let size = match device::file_size(input.as_raw_fd()) {
+      Ok(size) if size > 0 => size,
+      // procfs and sysfs files have data but no size, the tokio engine reads them to the end
+      _ => {
+        eprintln!("ruplica: warning: the input reports no size, using the tokio engine");
+        return Ok(None);
+      }
+    };
+    let mut length = size.saturating_sub(skip);
+    if let Some(count) = source.count {
+      length = length.min((count * source.block_size) as u64);
+    }
+    let depth = (BUFFER_MEMORY / source.block_size)
+      .clamp(1, QUEUE_DEPTH)
+      .min(length.div_ceil(source.block_size as u64).max(1) as usize);
+    let mut buffers: Vec<AlignedBuffer> = (0..depth).map(|_| AlignedBuffer::new(source.block_size, DIRECT_ALIGNMENT)).collect();
+    let iovecs: Vec<libc::iovec> = buffers
+      .iter_mut()
+      .map(|buffer| libc::iovec { iov_base: buffer.as_mut_slice().as_mut_ptr() as *mut libc::c_void, iov_len: buffer.len() })
+      .collect();
+    // safety: the buffers live as long as the ring, they are dropped after it
+    let fixed = match unsafe { ring.submitter().register_buffers(&iovecs) } {
+      Ok(()) => true,
+      Err(e) => {
+        // usually RLIMIT_MEMLOCK, plain reads and writes work without it
+        tracing::debug!("Cannot register buffers with io_uring ({}), using unregistered ones", e);
+        false
+      }
+    };
+    let watch = SourceWatch::new(input_path, source.on_change)?;
+    {
+      let mut statistics = dd_context.read_statistics.lock().await;
+      statistics.init();
+      statistics.estimated_bytes = length;
+    }
+    dd_context.write_statistics.lock().await.init();
+
+    let mut copy = UringCopy {
+      ring,
+      input,
+      output,
+      input_offset: skip,
+      output_offset: seek,
+      length,
+      block_size: source.block_size,
+      direct_input: source.direct,
+      direct_output,
+      buffers,
+      fixed,
+      fsync: sink.fsync,
+      fdatasync: sink.fdatasync,
+      watch,
+      read_statistics: dd_context.read_statistics.clone(),
+      write_statistics: dd_context.write_statistics.clone(),
+      source_changed: dd_context.source_changed.clone(),
+    };
+    if let Err(e) = copy.probe() {
+      eprintln!("ruplica: warning: io_uring cannot copy these files ({}), using the tokio engine", e);
+      return Ok(None);
+    }
+    Ok(Some(copy))
+  }
+
+  /// Reads the first block and writes nothing at the output offset, so a kernel that has a ring
+  /// but not the operations, or files it cannot do them on, send the copy to the tokio engine.
+  fn probe(&mut self) -> std::io::Result<()> {
+    let failed = |e: Failure| std::io::Error::other(e.to_string());
+    self.read(0, &Slot { offset: 0, length: 0, written: 0 }).map_err(failed)?;
+    let empty = Slot { offset: 0, length: 0, written: 0 };
+    self.write(0, &empty).map_err(failed)?;
+    self.ring.submit_and_wait(2)?;
+    let results: Vec<i32> = self.ring.completion().map(|entry| entry.result()).collect();
+    match results.into_iter().find(|result| [-libc::EINVAL, -libc::EOPNOTSUPP].contains(result)) {
+      Some(result) => Err(std::io::Error::from_raw_os_error(-result)),
+      None => Ok(()),
+    }
+  }
+
+  fn push(&mut self, entry: squeue::Entry) -> Result<(), Failure> {
+    // safety: the buffer an entry points to stays untouched until its completion arrives
+    while unsafe { self.ring.submission().push(&entry) }.is_err() {
+      self.ring.submit().map_err(|e| Failure::read(&e, self.input_offset as usize))?;
+    }
+    Ok(())
+  }
+
+  fn read(&mut self, index: usize, slot: &Slot) -> Result<(), Failure> {
+    let offset = self.input_offset + slot.offset + slot.length as u64;
+    if self.direct_input && !slot.length.is_multiple_of(DIRECT_ALIGNMENT) {
+      device::set_direct(self.input.as_raw_fd(), false).map_err(|e| Failure::read(&e, offset as usize))?;
+    }
+    let fd = types::Fd(self.input.as_raw_fd());
+    // safety: length < the buffer size
+    let buffer = unsafe { self.buffers[index].as_mut_slice().as_mut_ptr().add(slot.length) };
+    // always the rest of a whole block, O_DIRECT wants aligned lengths and the kernel stops at the end
+    let length = (self.block_size - slot.length) as u32;
+    let entry = match self.fixed {
+      true => opcode::ReadFixed::new(fd, buffer, length, index as u16).offset(offset).build(),
+      false => opcode::Read::new(fd, buffer, length).offset(offset).build(),
+    };
+    self.push(entry.user_data(index as u64))
+  }
+
+  fn write(&mut self, index: usize, slot: &Slot) -> Result<(), Failure> {
+    let length = slot.length - slot.written;
+    if let Some(block) = self.direct_output {
+      if !slot.length.is_multiple_of(block) {
+        device::set_direct(self.output.as_raw_fd(), false).map_err(|e| Failure::write(&e, (self.output_offset + slot.offset) as usize))?;
+      }
+    }
+    let fd = types::Fd(self.output.as_raw_fd());
+    // safety: written < length <= the buffer size
+    let buffer = unsafe { self.buffers[index].as_slice().as_ptr().add(slot.written) };
+    let offset = self.output_offset + slot.offset + slot.written as u64;
+    let entry = match self.fixed {
+      true => opcode::WriteFixed::new(fd, buffer, length as u32, index as u16).offset(offset).build(),
+      false => opcode::Write::new(fd, buffer, length as u32).offset(offset).build(),
+    };
+    self.push(entry.user_data(WRITE | index as u64))
+  }
+
+  /// Re-stats the input, records a change and stops the copy if it should.
+  fn check_source(&mut self, position: u64) -> Result<(), Failure> {
+    let Some(watch) = self.watch.as_mut() else {
+      return Ok(());
+    };
+    let Some(change) = watch.check() else {
+      return Ok(());
+    };
+    self.source_changed.blocking_lock().get_or_insert(change.clone());
+    match watch.failure(&change, position as usize) {
+      Some(failure) => Err(failure),
+      None => Ok(()),
+    }
+  }
+
+  fn pump(&mut self, task: &Mutex<Task>) -> Result<(), Failure> {
+    let mut slots: Vec<Option<Slot>> = vec![None; self.buffers.len()];
+    let mut next = 0u64;
+    let mut in_flight = 0;
+    // after a failure nothing new is started, but what is in flight still owns its buffer
+    let mut failure: Option<Failure> = None;
+    loop {
+      if failure.is_none() {
+        let free: Vec<usize> = (0..slots.len()).filter(|&index| slots[index].is_none()).collect();
+        for index in free {
+          if next >= self.length {
+            break;
+          }
+          let slot = Slot { offset: next, length: 0, written: 0 };
+          self.read(index, &slot)?;
+          slots[index] = Some(slot);
+          next += self.block_size as u64;
+          in_flight += 1;
+        }
+      }
+      if in_flight == 0 {
+        break;
+      }
+      self.ring.submit_and_wait(1).map_err(|e| Failure::read(&e, self.input_offset as usize))?;
+      let completions: Vec<(u64, i32)> = self.ring.completion().map(|entry| (entry.user_data(), entry.result())).collect();
+      for (user_data, result) in completions {
+        in_flight -= 1;
+        let index = (user_data & !WRITE) as usize;
+        let Some(mut slot) = slots[index].take() else {
+          continue;
+        };
+        if user_data & WRITE == 0 {
+          if result < 0 {
+            let error = std::io::Error::from_raw_os_error(-result);
+            self.read_statistics.blocking_lock().add_error();
+            failure.get_or_insert(Failure::read(&error, (self.input_offset + slot.offset) as usize));
+            continue;
+          }
+          if result == 0 {
+            // the input ended early, nothing after this is read
+            self.length = self.length.min(slot.offset + slot.length as u64);
+          }
+          let wanted = (self.length - slot.offset.min(self.length)).min(self.block_size as u64) as usize;
+          slot.length = (slot.length + result as usize).min(wanted);
+          if wanted == 0 || failure.is_some() {
+            continue;
+          }
+          if slot.length < wanted {
+            // reads may come back short, the rest of the block is asked for again
+            self.read(index, &slot)?;
+            slots[index] = Some(slot);
+            in_flight += 1;
+            continue;
+          }
+          self.read_statistics.blocking_lock().add_bytes(slot.length as u64);
+          self.write(index, &slot)?;
+          slots[index] = Some(slot);
+          in_flight += 1;
+        } else {
+          let position = (self.output_offset + slot.offset + slot.written as u64) as usize;
+          let written = match result {
+            ..0 => Err(std::io::Error::from_raw_os_error(-result)),
+            0 => Err(std::io::ErrorKind::WriteZero.into()),
+            _ => Ok(result as usize),
+          };
+          match written {
+            Err(e) => {
+              failure.get_or_insert(Failure::write(&e, position));
+            },
+            Ok(written) => {
+              slot.written += written;
+              if slot.written < slot.length {
+                self.write(index, &slot)?;
+                slots[index] = Some(slot);
+                in_flight += 1;
+              } else {
+                self.write_statistics.blocking_lock().add_bytes(slot.length as u64);
+              }
+            },
+          }
+        }
+      }
+      task.blocking_lock().ping();
+      if failure.is_none() && self.watch.as_ref().is_some_and(|watch| watch.due()) {
+        if let Err(e) = self.check_source(self.input_offset + next) {
+          failure = Some(e);
+        }
+      }
+    }
+    if let Some(failure) = failure {
+      return Err(failure);
+    }
+    self.check_source(self.input_offset + self.length)
+  }
+
+  /// Copies everything, then syncs the output if asked to.
+  pub fn copy(&mut self, task: &Mutex<Task>) -> Result<(), Failure> {
+    let outcome = self.pump(task);
+    // also after a failure, so the summary matches the bytes that made it
+    self.read_statistics.blocking_lock().count_records(self.block_size as u64);
+    self.write_statistics.blocking_lock().count_records(self.block_size as u64);
+    outcome?;
+
+    let position = (self.output_offset + self.length) as usize;
+    let synced = match (self.fsync, self.fdatasync) {
+      (true, _) => device::fsync(self.output.as_raw_fd()),
+      (false, true) => device::fdatasync(self.output.as_raw_fd()),
+      (false, false) => Ok(()),
+    };
+    synced.map_err(|e| Failure::write(&e, position))
+  }
+
+  /// Runs the copy as one task in place of the source and the sink.
+  #[tracing::instrument(skip(self, dd_context), level="debug", ret, err)]
+  pub async fn run(mut self, dd_context: Arc<Mutex<DdContext>>) -> Result<(), IoError> {
+    let task = dd_context.lock().await.new_task("Uring").await;
+    let notifications = dd_context.lock().await.main_notifications.clone();
+    let failure = dd_context.lock().await.failure.clone();
+    tokio::spawn(async move {
+      task.lock().await.change_state(TaskStatus::Running);
+      let running = task.clone();
+      let outcome = tokio::task::spawn_blocking(move || self.copy(&running))
+        .await
+        .unwrap_or_else(|e| Err(Failure::from(IoError::ReadError(format!("copy task failed: {}", e)))));
+      match outcome {
+        Ok(()) => task.lock().await.complete(0),
+        Err(e) => {
+          tracing::error!("Copying failed: {}", e);
+          task.lock().await.fail(-2, e.to_string());
+          DdContext::record_failure(&failure, e).await;
+        }
+      }
+      notifications.notify_one();
+    });
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn test_uring_copy() {
+    let dir = tempfile::tempdir().unwrap();
+    let data: Vec<u8> = (0..1_000_003u32).map(|i| (i % 253) as u8).collect();
+    std::fs::write(dir.path().join("input"), &data).unwrap();
+    let source = SourceConfig { input_file: Some(dir.path().join("input")), block_size: 4096, skip: 1, ..SourceConfig::default() };
+    let sink = SinkConfig { output_file: Some(dir.path().join("output")), block_size: 4096, seek: 2, ..SinkConfig::default() };
+    assert!(eligible(&source, &sink));
+    let context = DdContext::new();
+    let Some(mut copy) = UringCopy::open(&source, &sink, &context).await.unwrap() else {
+      // no io_uring in this sandbox
+      return;
+    };
+    let task = Mutex::new(Task::new());
+    tokio::task::spawn_blocking(move || copy.copy(&task).unwrap()).await.unwrap();
+
+    let output = std::fs::read(dir.path().join("output")).unwrap();
+    assert_eq!(&output[..8192], &[0u8; 8192]);
+    assert_eq!(&output[8192..], &data[4096..]);
+    let statistics = context.read_statistics.lock().await;
+    assert_eq!(statistics.total_bytes_read, (data.len() - 4096) as u64);
+    assert_eq!((statistics.full_records, statistics.partial_records), (243, 1));
+  }
+}
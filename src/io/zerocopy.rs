@@ -0,0 +1,416 @@
+use std::os::fd::{AsRawFd, RawFd};
+use std::sync::Arc;
+
+use tokio::sync::Mutex;
+
+use crate::environment::statistics::{DdContext, ReadStatistics, Task, TaskStatus, WriteStatistics};
+use crate::io::device;
+use crate::io::error::{Failure, IoError};
+use crate::io::sink::config::SinkConfig;
+use crate::io::sink::core::DataSink;
+use crate::io::source::config::SourceConfig;
+use crate::io::source::core::DataSource;
+use crate::io::source::snapshot::SourceWatch;
+
+/// Most bytes asked of the kernel in one call, rounded down to whole blocks.
+const CHUNK_SIZE: usize = 8 << 20;
+
+/// How bytes get from the input to the output without passing through the pipeline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+  /// Both ends are files, lets btrfs and xfs share extents instead of copying
+  CopyFileRange,
+  /// One end is a pipe
+  Splice,
+  /// The input is a file
+  Sendfile,
+  /// Plain reads and writes, when the kernel accepts none of the above
+  ReadWrite,
+}
+
+/// Copies without transforming anything: no hashing, no conversions, no re-blocking.
+pub fn eligible(source: &SourceConfig, sink: &SinkConfig) -> bool {
+  passthrough(source, sink) && !source.direct && !sink.direct
+}
+
+/// True when every block goes to the output unchanged and nothing needs to see it on the way,
+/// so engines other than the pipeline can copy it.
+pub fn passthrough(source: &SourceConfig, sink: &SinkConfig) -> bool {
+  !source.enable_hash
+    && !sink.enable_hash
+    && !sink.verify
+    && sink.journal.is_none()
+    && source.resume_offset == 0
+    && !source.noerror
+    && !source.pad_blocks
+    && !source.sparse
+    && !sink.sparse
+    && !source.nocache
+    && !sink.nocache
+    && source.block_size == sink.block_size
+}
+
+/// One end of the copy.
+#[derive(Debug)]
+struct End {
+  // keeps a named file open, None for stdin and stdout
+  _file: Option<std::fs::File>,
+  fd: RawFd,
+  // regular files and block devices are read and written at explicit offsets
+  positional: bool,
+  pipe: bool,
+  offset: u64,
+  size: u64,
+}
+
+impl End {
+  fn new(file: Option<std::fs::File>, fd: RawFd) -> std::io::Result<Self> {
+    let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstat(fd, &mut stat) } == -1 {
+      return Err(std::io::Error::last_os_error());
+    }
+    let format = stat.st_mode & libc::S_IFMT;
+    let positional = format == libc::S_IFREG || format == libc::S_IFBLK;
+    // stdin and stdout redirected from or to a file continue where the shell left them
+    let offset = match positional {
+      true => unsafe { libc::lseek(fd, 0, libc::SEEK_CUR) }.max(0) as u64,
+      false => 0,
+    };
+    Ok(End { _file: file, fd, positional, pipe: format == libc::S_IFIFO, offset, size: stat.st_size as u64 })
+  }
+}
+
+fn is_unsupported(errno: Option<i32>) -> bool {
+  matches!(errno, Some(libc::EINVAL | libc::ENOSYS | libc::EXDEV | libc::EOPNOTSUPP | libc::EBADF))
+}
+
+fn result(transferred: isize) -> std::io::Result<usize> {
+  match transferred {
+    -1 => Err(std::io::Error::last_os_error()),
+    _ => Ok(transferred as usize),
+  }
+}
+
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+pub struct ZeroCopy {
+  input: End,
+  output: End,
+  pub method: Method,
+  block_size: usize,
+  remaining: Option<u64>,
+  fsync: bool,
+  fdatasync: bool,
+  watch: Option<SourceWatch>,
+  #[derivative(Debug="ignore")]
+  buffer: Vec<u8>,
+  #[derivative(Debug="ignore")]
+  read_statistics: Arc<Mutex<ReadStatistics>>,
+  #[derivative(Debug="ignore")]
+  write_statistics: Arc<Mutex<WriteStatistics>>,
+  #[derivative(Debug="ignore")]
+  source_changed: Arc<Mutex<Option<String>>>,
+}
+
+impl ZeroCopy {
+  /// Opens both ends, None when they need the pipeline after all: skip, seek or count on a stream.
+  pub async fn open(source: &SourceConfig, sink: &SinkConfig, dd_context: &DdContext) -> Result<Option<Self>, Failure> {
+    let skip = (source.skip * source.block_size) as u64;
+    let seek = (sink.seek * sink.block_size) as u64;
+    let mut input = match &source.input_file {
+      Some(path) => {
+        let file = DataSource::open_file(path, source).await?.0.into_std().await;
+        let fd = file.as_raw_fd();
+        End::new(Some(file), fd)
+      },
+      None => End::new(None, libc::STDIN_FILENO),
+    }
+    .map_err(|e| IoError::InputFileOpenError(e.to_string()).caused_by(&e))?;
+    // count on a stream counts reads unless fullblock makes them whole blocks
+    if !input.positional && (skip > 0 || (source.count.is_some() && !source.fullblock)) {
+      return Ok(None);
+    }
+    let mut output = match &sink.output_file {
+      Some(path) => {
+        let file = DataSink::open_file(path, sink, seek).await?.0.into_std().await;
+        let fd = file.as_raw_fd();
+        End::new(Some(file), fd)
+      },
+      None => End::new(None, libc::STDOUT_FILENO),
+    }
+    .map_err(|e| IoError::OutputFileOpenError(e.to_string()).caused_by(&e))?;
+    if !output.positional && seek > 0 {
+      return Ok(None);
+    }
+    input.offset += skip;
+    output.offset += seek;
+
+    let method = match (input.positional, output.positional) {
+      (true, true) => Method::CopyFileRange,
+      _ if input.pipe || output.pipe => Method::Splice,
+      (true, false) => Method::Sendfile,
+      _ => Method::ReadWrite,
+    };
+    let remaining = source.count.map(|count| (count * source.block_size) as u64);
+    let mut estimated = input.size.saturating_sub(input.offset);
+    if let Some(remaining) = remaining {
+      estimated = estimated.min(remaining);
+    }
+    let watch = match &source.input_file {
+      Some(path) => SourceWatch::new(path, source.on_change)?,
+      None => None,
+    };
+    {
+      let mut statistics = dd_context.read_statistics.lock().await;
+      statistics.init();
+      statistics.estimated_bytes = estimated;
+    }
+    dd_context.write_statistics.lock().await.init();
+
+    Ok(Some(ZeroCopy {
+      input,
+      output,
+      method,
+      block_size: source.block_size,
+      remaining,
+      fsync: sink.fsync,
+      fdatasync: sink.fdatasync,
+      watch,
+      buffer: Vec::new(),
+      read_statistics: dd_context.read_statistics.clone(),
+      write_statistics: dd_context.write_statistics.clone(),
+      source_changed: dd_context.source_changed.clone(),
+    }))
+  }
+
+  /// The method to use after the kernel refused `self.method` for these two ends.
+  fn fallback(&self) -> Method {
+    match self.method {
+      Method::CopyFileRange if self.input.positional => Method::Sendfile,
+      _ => Method::ReadWrite,
+    }
+  }
+
+  /// Moves up to `length` bytes with the current method, 0 at the end of the input.
+  /// Offsets are advanced by the caller.
+  fn transfer(&mut self, length: usize) -> Result<usize, Failure> {
+    let (input, output) = (self.input.fd, self.output.fd);
+    // the kernel advances these copies, ours only move once the bytes are counted
+    let mut input_offset = self.input.offset as libc::loff_t;
+    let mut output_offset = self.output.offset as libc::loff_t;
+    let input_position = match self.input.positional {
+      true => &mut input_offset as *mut libc::loff_t,
+      false => std::ptr::null_mut(),
+    };
+    let output_position = match self.output.positional {
+      true => &mut output_offset as *mut libc::loff_t,
+      false => std::ptr::null_mut(),
+    };
+    let transferred = match self.method {
+      Method::CopyFileRange => result(unsafe { libc::copy_file_range(input, input_position, output, output_position, length, 0) }),
+      Method::Splice => result(unsafe { libc::splice(input, input_position, output, output_position, length, libc::SPLICE_F_MOVE) }),
+      Method::Sendfile => {
+        // sendfile writes at the file offset of the output
+        if self.output.positional && unsafe { libc::lseek(output, output_offset, libc::SEEK_SET) } == -1 {
+          return Err(Failure::write(&std::io::Error::last_os_error(), self.output.offset as usize));
+        }
+        result(unsafe { libc::sendfile(output, input, input_position, length) })
+      },
+      Method::ReadWrite => return self.read_write(length),
+    };
+    transferred.map_err(|e| match e.raw_os_error() {
+      // only the output can run out of space or be closed
+      Some(libc::ENOSPC | libc::EDQUOT | libc::EFBIG | libc::EPIPE) => Failure::write(&e, self.output.offset as usize),
+      _ => Failure::read(&e, self.input.offset as usize),
+    })
+  }
+
+  fn read_write(&mut self, length: usize) -> Result<usize, Failure> {
+    let length = length.min(self.block_size);
+    self.buffer.resize(length, 0);
+    let (input, output) = (&self.input, &self.output);
+    let read = result(unsafe {
+      match input.positional {
+        true => libc::pread(input.fd, self.buffer.as_mut_ptr() as *mut libc::c_void, length, input.offset as libc::off_t),
+        false => libc::read(input.fd, self.buffer.as_mut_ptr() as *mut libc::c_void, length),
+      }
+    })
+    .map_err(|e| Failure::read(&e, input.offset as usize))?;
+    let mut written = 0;
+    while written < read {
+      let pending = &self.buffer[written..read];
+      let offset = output.offset + written as u64;
+      let done = result(unsafe {
+        match output.positional {
+          true => libc::pwrite(output.fd, pending.as_ptr() as *const libc::c_void, pending.len(), offset as libc::off_t),
+          false => libc::write(output.fd, pending.as_ptr() as *const libc::c_void, pending.len()),
+        }
+      })
+      .map_err(|e| Failure::write(&e, offset as usize))?;
+      if done == 0 {
+        return Err(Failure::write(&std::io::ErrorKind::WriteZero.into(), offset as usize));
+      }
+      written += done;
+    }
+    Ok(read)
+  }
+
+  /// Re-stats the input, records a change and stops the copy if it should.
+  fn check_source(&mut self) -> Result<(), Failure> {
+    let Some(watch) = self.watch.as_mut() else {
+      return Ok(());
+    };
+    let Some(change) = watch.check() else {
+      return Ok(());
+    };
+    self.source_changed.blocking_lock().get_or_insert(change.clone());
+    match watch.failure(&change, self.input.offset as usize) {
+      Some(failure) => Err(failure),
+      None => Ok(()),
+    }
+  }
+
+  /// Copies until the end of the input or `count` blocks, then syncs the output if asked to.
+  pub fn copy(&mut self, task: &Mutex<Task>) -> Result<(), Failure> {
+    let outcome = self.pump(task);
+    // also after a failure, so the summary matches the bytes that made it
+    self.read_statistics.blocking_lock().count_records(self.block_size as u64);
+    self.write_statistics.blocking_lock().count_records(self.block_size as u64);
+    outcome?;
+
+    let synced = match (self.fsync, self.fdatasync) {
+      (true, _) => device::fsync(self.output.fd),
+      (false, true) => device::fdatasync(self.output.fd),
+      (false, false) => Ok(()),
+    };
+    match synced {
+      // pipes and terminals cannot be synced, dd ignores that too
+      Err(e) if e.raw_os_error() != Some(libc::EINVAL) => Err(Failure::write(&e, self.output.offset as usize)),
+      _ => Ok(()),
+    }
+  }
+
+  fn pump(&mut self, task: &Mutex<Task>) -> Result<(), Failure> {
+    let chunk = (CHUNK_SIZE / self.block_size).max(1) * self.block_size;
+    tracing::info!("Copying with {:?}", self.method);
+    loop {
+      let length = match self.remaining {
+        Some(0) => break,
+        Some(remaining) => remaining.min(chunk as u64) as usize,
+        None => chunk,
+      };
+      let copied = match self.transfer(length) {
+        Ok(copied) => copied,
+        Err(e) if self.method != Method::ReadWrite && is_unsupported(e.errno) => {
+          let next = self.fallback();
+          tracing::debug!("{:?} does not work for these files ({}), using {:?}", self.method, e, next);
+          self.method = next;
+          continue;
+        },
+        Err(e) => {
+          self.read_statistics.blocking_lock().add_error();
+          return Err(e);
+        }
+      };
+      if copied == 0 {
+        break;
+      }
+      self.input.offset += copied as u64;
+      self.output.offset += copied as u64;
+      if let Some(remaining) = self.remaining.as_mut() {
+        *remaining -= copied as u64;
+      }
+      self.read_statistics.blocking_lock().add_bytes(copied as u64);
+      self.write_statistics.blocking_lock().add_bytes(copied as u64);
+      task.blocking_lock().ping();
+      if self.watch.as_ref().is_some_and(|watch| watch.due()) {
+        self.check_source()?;
+      }
+    }
+    self.check_source()
+  }
+
+  /// Runs the copy as one task in place of the source and the sink.
+  #[tracing::instrument(skip(self, dd_context), level="debug", ret, err)]
+  pub async fn run(mut self, dd_context: Arc<Mutex<DdContext>>) -> Result<(), IoError> {
+    let task = dd_context.lock().await.new_task("ZeroCopy").await;
+    let notifications = dd_context.lock().await.main_notifications.clone();
+    let failure = dd_context.lock().await.failure.clone();
+    tokio::spawn(async move {
+      task.lock().await.change_state(TaskStatus::Running);
+      let running = task.clone();
+      let outcome = tokio::task::spawn_blocking(move || self.copy(&running))
+        .await
+        .unwrap_or_else(|e| Err(Failure::from(IoError::ReadError(format!("copy task failed: {}", e)))));
+      match outcome {
+        Ok(()) => task.lock().await.complete(0),
+        Err(e) => {
+          tracing::error!("Copying failed: {}", e);
+          task.lock().await.fail(-2, e.to_string());
+          DdContext::record_failure(&failure, e).await;
+        }
+      }
+      notifications.notify_one();
+    });
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  async fn copy(source: SourceConfig, sink: SinkConfig) -> (ZeroCopy, DdContext) {
+    let context = DdContext::new();
+    let mut zero_copy = ZeroCopy::open(&source, &sink, &context).await.unwrap().unwrap();
+    let task = Mutex::new(Task::new());
+    let zero_copy = tokio::task::spawn_blocking(move || {
+      zero_copy.copy(&task).unwrap();
+      zero_copy
+    })
+    .await
+    .unwrap();
+    (zero_copy, context)
+  }
+
+  #[tokio::test]
+  async fn test_zero_copy_with_skip_seek_and_count() {
+    let dir = tempfile::tempdir().unwrap();
+    let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+    std::fs::write(dir.path().join("input"), &data).unwrap();
+    let source = SourceConfig { input_file: Some(dir.path().join("input")), block_size: 4096, skip: 2, count: Some(10), ..SourceConfig::default() };
+    let sink = SinkConfig { output_file: Some(dir.path().join("output")), block_size: 4096, seek: 1, ..SinkConfig::default() };
+    assert!(eligible(&source, &sink));
+    let (zero_copy, context) = copy(source, sink).await;
+    assert_eq!(zero_copy.method, Method::CopyFileRange);
+
+    let output = std::fs::read(dir.path().join("output")).unwrap();
+    assert_eq!(output.len(), 11 * 4096);
+    assert_eq!(&output[..4096], &[0u8; 4096]);
+    assert_eq!(&output[4096..], &data[2 * 4096..12 * 4096]);
+    let statistics = context.write_statistics.lock().await;
+    assert_eq!((statistics.full_records, statistics.partial_records), (10, 0));
+  }
+
+  #[tokio::test]
+  async fn test_zero_copy_counts_partial_record() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("input"), vec![7u8; 10_000]).unwrap();
+    let source = SourceConfig { input_file: Some(dir.path().join("input")), block_size: 4096, ..SourceConfig::default() };
+    let sink = SinkConfig { output_file: Some(dir.path().join("output")), block_size: 4096, ..SinkConfig::default() };
+    let (_, context) = copy(source, sink).await;
+    assert_eq!(std::fs::read(dir.path().join("output")).unwrap(), vec![7u8; 10_000]);
+    let statistics = context.read_statistics.lock().await;
+    assert_eq!((statistics.full_records, statistics.partial_records), (2, 1));
+    assert_eq!(statistics.total_bytes_read, 10_000);
+  }
+
+  #[test]
+  fn test_transforms_need_the_pipeline() {
+    let source = SourceConfig { enable_hash: true, ..SourceConfig::default() };
+    assert!(!eligible(&source, &SinkConfig::default()));
+    let sink = SinkConfig { block_size: 4096, ..SinkConfig::default() };
+    assert!(!eligible(&SourceConfig::default(), &sink));
+    assert!(eligible(&SourceConfig::default(), &SinkConfig::default()));
+  }
+}
@@ -37,7 +37,8 @@ pub fn init_subscriber() -> Result<(), Box<dyn std::error::Error>> {
                 .with_ansi(true)
                 .with_line_number(true)
                 .with_file(true)
-                .with_thread_ids(true),
+                .with_thread_ids(true)
+                .with_writer(std::io::stderr),
         );
     tracing::subscriber::set_global_default(subscriber)
         .expect("Unable to set a global logger instance");
@@ -1,54 +1,313 @@
+pub mod bench;
 pub mod config;
 pub mod io;
 pub mod environment;
 pub mod logger;
-pub mod taskstate;
+pub mod manifest;
 
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use config::Args;
-use tokio::sync::Mutex;
+use std::time::Duration;
+use config::{Args, Conv, Engine, ErrorFormat, SourceChange, Status};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{Mutex, Notify};
 
+use crate::io::error::{DdError, ErrorKind, IoError};
+use crate::io::journal::Journal;
 use crate::io::source::config::SourceConfig;
 use crate::io::sink::config::SinkConfig;
 
+/// How many times `--on-source-change retry` copies before giving up.
+const SOURCE_CHANGE_ATTEMPTS: usize = 3;
+
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    logger::init_subscriber()?;
+async fn main() -> ExitCode {
+    if let Err(e) = logger::init_subscriber() {
+        eprintln!("ruplica: {}", e);
+        return ExitCode::from(ErrorKind::Other.exit_code());
+    }
 
-    let args = Args::create();
-    let sink_cfg = SinkConfig::from(&args);
-    let source_cfg = SourceConfig::from(&args);
-    let (sink_channel, source_channel) = tokio::sync::mpsc::channel(10);
+    let command_line = config::translate_operands(std::env::args_os());
+    let args = match Args::create(command_line.clone()) {
+        Ok(args) => args,
+        // --help and --version, and usage errors asked for as text, are printed the way clap does
+        Err(e) if !e.use_stderr() || config::requested_error_format(&command_line) == ErrorFormat::Text => e.exit(),
+        Err(e) => {
+            let e = DdError::from(e);
+            eprintln!("{}", e.to_json());
+            return ExitCode::from(e.kind().exit_code());
+        }
+    };
+    match run(&args).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            match args.error_format {
+                ErrorFormat::Text => eprintln!("ruplica: {}", e),
+                ErrorFormat::Json => eprintln!("{}", e.to_json()),
+            }
+            ExitCode::from(e.kind().exit_code())
+        }
+    }
+}
+
+async fn run(args: &Args) -> Result<(), DdError> {
+    environment::priority::apply(args.ioprio_class, args.ioprio_level, args.nice);
+
+    if let Some(check) = &args.check {
+        let input = args.input_file.clone().map(PathBuf::from);
+        return manifest::check::run(&PathBuf::from(check), args.manifest_format, input, args.ibs()).await;
+    }
+
+    if args.bench {
+        return bench::run(args).await;
+    }
+
+    if args.rescue.is_some() {
+        return rescue(args).await;
+    }
+
+    let tuned;
+    let args = match args.bs {
+        Some(config::size::BlockSize::Auto) => {
+            tuned = bench::tune(args).await?;
+            &tuned
+        },
+        _ => args,
+    };
+
+    let mut attempt = 1;
+    loop {
+        match copy(args).await {
+            Err(DdError::CopyFailed(failure))
+                if matches!(failure.error, IoError::SourceChanged(_))
+                    && args.on_source_change == SourceChange::Retry
+                    // what already went to stdout cannot be taken back
+                    && args.output_file.is_some()
+                    && attempt < SOURCE_CHANGE_ATTEMPTS =>
+            {
+                attempt += 1;
+                eprintln!("ruplica: {}, starting over (attempt {}/{})", failure, attempt, SOURCE_CHANGE_ATTEMPTS);
+            }
+            outcome => return outcome,
+        }
+    }
+}
+
+/// Starts the io_uring engine, false when this copy has to go through the tokio one instead.
+#[cfg(feature = "uring")]
+async fn start_uring(args: &Args, source_cfg: &SourceConfig, sink_cfg: &SinkConfig, global_state: &Arc<Mutex<environment::statistics::DdContext>>) -> Result<bool, DdError> {
+    if args.manifest.is_some() || !io::uring::eligible(source_cfg, sink_cfg) {
+        eprintln!("ruplica: warning: --engine uring only copies files without hashing or conversions, using the tokio engine");
+        return Ok(false);
+    }
+    if args.throttled() {
+        eprintln!("ruplica: warning: --engine uring cannot pace its reads, using the tokio engine");
+        return Ok(false);
+    }
+    let uring = io::uring::UringCopy::open(source_cfg, sink_cfg, &*global_state.lock().await).await?;
+    match uring {
+        Some(uring) => {
+            uring.run(global_state.clone()).await?;
+            Ok(true)
+        },
+        None => Ok(false),
+    }
+}
+
+#[cfg(not(feature = "uring"))]
+async fn start_uring(_args: &Args, _source_cfg: &SourceConfig, _sink_cfg: &SinkConfig, _global_state: &Arc<Mutex<environment::statistics::DdContext>>) -> Result<bool, DdError> {
+    eprintln!("ruplica: warning: built without io_uring support, using the tokio engine");
+    Ok(false)
+}
+
+/// One copy from the input to the output.
+async fn copy(args: &Args) -> Result<(), DdError> {
+    let mut sink_cfg = SinkConfig::from(args);
+    let mut source_cfg = SourceConfig::from(args);
+    let (sink_channel, source_channel) = tokio::sync::mpsc::channel(args.queue_depth());
     let global_state = Arc::new(Mutex::new(environment::statistics::DdContext::new()));
+
+    if let Some(journal_path) = args.journal.as_ref().or(args.resume.as_ref()) {
+        let journal_path = PathBuf::from(journal_path);
+        let record = match &args.resume {
+            Some(_) => {
+                let record = Journal::load(&journal_path)?;
+                io::journal::resume(&record, &mut source_cfg, &mut sink_cfg).await?;
+                eprintln!("Resuming job {} after {} committed bytes", record.job_id, record.committed);
+                record
+            },
+            None => Journal::start(global_state.lock().await.job_id, &source_cfg, &sink_cfg)?,
+        };
+        // a resumed copy keeps the job id it was started with
+        global_state.lock().await.job_id = record.job_id;
+        sink_cfg.journal = Some((journal_path, record));
+    }
     
     let main_notifications = global_state.lock().await.main_notifications.clone();
-    let _sender = sink_channel.clone(); // keep so that the channel is not dropped
 
-    io::source::core::DataSource::run(sink_channel, source_cfg, global_state.clone()).await?;
-    io::sink::core::DataSink::run(source_channel, sink_cfg, global_state.clone()).await?;
+    // installed before copying starts, SIGUSR1 would terminate us otherwise
+    let (quiet, transfer) = (args.status == Some(Status::None), args.status != Some(Status::Noxfer));
+    let signal_reporter = environment::progress::spawn_signal_reporter(&*global_state.lock().await, quiet, transfer)
+        .map_err(|e| DdError::OtherError(format!("cannot install signal handler: {}", e)))?;
+    let mut sigint = signal(SignalKind::interrupt()).map_err(|e| DdError::OtherError(e.to_string()))?;
+    let mut sigterm = signal(SignalKind::terminate()).map_err(|e| DdError::OtherError(e.to_string()))?;
+
+    let throttle = global_state.lock().await.throttle.clone();
+    throttle.set_limits(args.limits());
+    let mut control = None;
+    if let Some(path) = &args.control {
+        let reader = environment::control::spawn_control(PathBuf::from(path), throttle)
+            .map_err(|e| DdError::OtherError(format!("cannot reload the control file {} on SIGHUP: {}", path, e)))?;
+        control = Some(reader);
+    }
+
+    // nothing to hash or convert, the kernel can move the bytes itself
+    let mut zero_copy = None;
+    let uring = args.engine == Engine::Uring && start_uring(args, &source_cfg, &sink_cfg, &global_state).await?;
+    // paced and parallel reads only happen in the tokio engine
+    let parallel = args.readers > 1 || args.writers > 1;
+    if !uring && !parallel && !args.throttled() && !args.no_zero_copy && args.manifest.is_none() && io::zerocopy::eligible(&source_cfg, &sink_cfg) {
+        zero_copy = io::zerocopy::ZeroCopy::open(&source_cfg, &sink_cfg, &*global_state.lock().await).await?;
+    }
+    match zero_copy {
+        Some(zero_copy) => zero_copy.run(global_state.clone()).await?,
+        None if uring => {},
+        None => {
+            io::source::core::DataSource::run(sink_channel, source_cfg, global_state.clone()).await?;
+            io::sink::core::DataSink::run(source_channel, sink_cfg, global_state.clone()).await?;
+        }
+    }
+
+    let progress_stop = Arc::new(Notify::new());
+    let mut progress = None;
+    if args.status == Some(Status::Progress) {
+        let reporter = environment::progress::ProgressReporter::new(&*global_state.lock().await);
+        progress = Some(reporter.spawn(Duration::from_secs(1), progress_stop.clone()));
+    }
     
+    let mut interrupted = None;
     loop{
         {
             tracing::info!("Waiting for main_notifications");
-            main_notifications.notified().await;
+            tokio::select! {
+                _ = main_notifications.notified() => {},
+                _ = sigint.recv() => interrupted = Some("SIGINT"),
+                _ = sigterm.recv() => interrupted = Some("SIGTERM"),
+            }
             tracing::info!("Notified");
         }
-        global_state.lock().await.are_tasks_pending().await;
+        if interrupted.is_some() {
+            break;
+        }
         if global_state.lock().await.are_tasks_pending().await {
-            println!("Tasks are pending, waiting for them to complete");
+            tracing::debug!("Tasks are pending, waiting for them to complete");
             continue;
         } else {
-            println!("All tasks are complete");
+            tracing::debug!("All tasks are complete");
             break;
         }
     }
+    if let Some(progress) = progress {
+        progress_stop.notify_one();
+        let _ = progress.await;
+    }
 
-    println!("\n\nprinting out statistics");
-    global_state.lock().await.display_statistics().await;
+    signal_reporter.abort();
+    if let Some(control) = control {
+        control.abort();
+    }
     global_state.lock().await.display_tasks().await;
     for (k,v) in global_state.lock().await.task_status.iter() {
-        println!("Task: {}", k);
+        tracing::debug!("Task: {}", k);
         v.lock().await.display();
     }
+    if args.status != Some(Status::None) {
+        global_state.lock().await.display_statistics(args.status != Some(Status::Noxfer)).await;
+    }
+    if let Some(change) = global_state.lock().await.source_changed.lock().await.as_ref() {
+        eprintln!("WARNING: source changed while reading: {}", change);
+    }
+    if let Some(signal) = interrupted {
+        return Err(DdError::Interrupted(format!("received {}, the output is incomplete", signal)));
+    }
+    let failure = global_state.lock().await.failure.lock().await.take();
+    if let Some(failure) = failure {
+        return Err(failure.into());
+    }
+
+    let mismatches = global_state.lock().await.display_digests().await;
+    if !mismatches.is_empty() {
+        return Err(DdError::DigestMismatch(mismatches.join(", ")));
+    }
+
+    if args.verify {
+        if let Some(failure) = global_state.lock().await.display_verification().await {
+            return Err(DdError::VerificationFailed(failure));
+        }
+    }
+
+    if let Some(manifest) = &args.manifest {
+        let context = global_state.lock().await;
+        let digests = context.sink_digests.lock().await.clone();
+        let target = PathBuf::from(args.output_file.clone().unwrap_or_default());
+        manifest::write(&PathBuf::from(manifest), args.manifest_format, &target, &digests, context.job_id).await?;
+        eprintln!("Manifest written to {}", manifest);
+    }
+
+    let read_errors = global_state.lock().await.read_statistics.lock().await.total_errors;
+    if args.has_conv(Conv::Noerror) && read_errors > 0 {
+        return Err(DdError::PartialCopy(format!("{} unreadable input block(s) were skipped", read_errors)));
+    }
+    Ok(())
+}
+
+/// Rescue mode, see `io::rescue::core::Rescuer`. SIGINT and SIGTERM stop it after saving the mapfile.
+async fn rescue(args: &Args) -> Result<(), DdError> {
+    let config = io::rescue::config::RescueConfig::from(args);
+    let mapfile = config.mapfile.clone();
+    let global_state = Arc::new(Mutex::new(environment::statistics::DdContext::new()));
+    let stop = Arc::new(AtomicBool::new(false));
+    let mut sigint = signal(SignalKind::interrupt()).map_err(|e| DdError::OtherError(e.to_string()))?;
+    let mut sigterm = signal(SignalKind::terminate()).map_err(|e| DdError::OtherError(e.to_string()))?;
+    let (quiet, transfer) = (args.status == Some(Status::None), args.status != Some(Status::Noxfer));
+    let signal_reporter = environment::progress::spawn_signal_reporter(&*global_state.lock().await, quiet, transfer)
+        .map_err(|e| DdError::OtherError(format!("cannot install signal handler: {}", e)))?;
+    let progress_stop = Arc::new(Notify::new());
+    let mut progress = None;
+    if args.status == Some(Status::Progress) {
+        let reporter = environment::progress::ProgressReporter::new(&*global_state.lock().await);
+        progress = Some(reporter.spawn(Duration::from_secs(1), progress_stop.clone()));
+    }
+
+    let rescue = io::rescue::core::run(config, global_state.clone(), stop.clone());
+    tokio::pin!(rescue);
+    let mut interrupted = None;
+    let outcome = loop {
+        tokio::select! {
+            outcome = &mut rescue => break outcome,
+            _ = sigint.recv() => interrupted = Some("SIGINT"),
+            _ = sigterm.recv() => interrupted = Some("SIGTERM"),
+        }
+        // let the current read finish and the mapfile be saved
+        stop.store(true, Ordering::Relaxed);
+    };
+
+    if let Some(progress) = progress {
+        progress_stop.notify_one();
+        let _ = progress.await;
+    }
+    signal_reporter.abort();
+    if args.status != Some(Status::None) {
+        global_state.lock().await.display_statistics(args.status != Some(Status::Noxfer)).await;
+    }
+    let lost = outcome?;
+    if let Some(signal) = interrupted {
+        return Err(DdError::Interrupted(format!("received {}, resume with the same --rescue {}", signal, mapfile.display())));
+    }
+    if lost > 0 {
+        return Err(DdError::PartialCopy(format!("{} bytes could not be read, see {}", lost, mapfile.display())));
+    }
     Ok(())
 }
@@ -0,0 +1,138 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use tokio::sync::Mutex;
+
+use crate::environment::statistics::DdContext;
+use crate::io::digest::Digests;
+use crate::io::error::{DdError, IoError};
+use crate::io::source::config::SourceConfig;
+use crate::io::source::core::DataSource;
+use crate::manifest::{self, ManifestFormat};
+
+/// Streams `input` through a `DataSource`, hashing only with the algorithms `expected` names.
+#[tracing::instrument(skip(expected), level="debug", err)]
+async fn digest_input(input: &Path, expected: &Digests, block_size: usize) -> Result<Digests, DdError> {
+  let config = SourceConfig {
+    input_file: Some(input.to_path_buf()),
+    buffer_size: block_size,
+    block_size,
+    enable_hash: !expected.is_empty(),
+    enable_crc32: expected.crc32.is_some(),
+    enable_sha3: expected.sha3_512.is_some(),
+    enable_blake2b: expected.blake2b.is_some(),
+    ..SourceConfig::default()
+  };
+  let dd_context = Arc::new(Mutex::new(DdContext::new()));
+  let (sender, mut receiver) = tokio::sync::mpsc::channel(10);
+  DataSource::run(sender, config, dd_context.clone()).await?;
+
+  // nothing is written in check mode, the data is only drained from the channel
+  let mut reached_eof = false;
+  while let Some(chunk) = receiver.recv().await {
+    if chunk.is_end() {
+      reached_eof = true;
+      break;
+    }
+  }
+  drop(receiver);
+  if !reached_eof {
+    return Err(IoError::ReadError(input.display().to_string()).into());
+  }
+
+  let digests = dd_context.lock().await.source_digests.lock().await.clone();
+  Ok(digests)
+}
+
+/// Verifies every entry of the manifest, printing `<path>: OK` or `<path>: FAILED` like `b2sum -c`.
+/// When `input` is given it is checked against all entries instead of the paths they name.
+#[tracing::instrument(level="debug", err)]
+pub async fn run(manifest_file: &Path, format: ManifestFormat, input: Option<PathBuf>, block_size: usize) -> Result<(), DdError> {
+  let content = tokio::fs::read_to_string(manifest_file).await.map_err(|e| IoError::ManifestReadError(e.to_string()))?;
+  let entries = manifest::parse(&content, format)?;
+  if entries.is_empty() {
+    return Err(IoError::ManifestReadError(format!("{}: no properly formatted checksum lines found", manifest_file.display())).into());
+  }
+
+  let mut mismatched = 0;
+  let mut unreadable = 0;
+  for (path, expected) in entries.iter() {
+    let source = input.clone().unwrap_or_else(|| path.clone());
+    match digest_input(&source, expected, block_size).await {
+      Ok(computed) if expected.mismatches(&computed).is_empty() => println!("{}: OK", path.display()),
+      Ok(_) => {
+        mismatched += 1;
+        println!("{}: FAILED", path.display());
+      },
+      Err(e) => {
+        unreadable += 1;
+        eprintln!("{}: {}", source.display(), e);
+        println!("{}: FAILED open or read", path.display());
+      },
+    }
+  }
+
+  if unreadable > 0 {
+    eprintln!("WARNING: {} listed file(s) could not be read", unreadable);
+  }
+  if mismatched > 0 {
+    eprintln!("WARNING: {} computed checksum(s) did NOT match", mismatched);
+  }
+  if mismatched + unreadable > 0 {
+    return Err(DdError::DigestMismatch(format!("{} of {} manifest entries failed verification", mismatched + unreadable, entries.len())));
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::io::digest::{HashSelection, Hashers};
+
+  fn manifest_for(dir: &Path, name: &str, data: &[u8]) -> PathBuf {
+    let target = dir.join(name);
+    std::fs::write(&target, data).unwrap();
+    let mut hashers = Hashers::new(HashSelection { blake2b: true, sha3: false, crc32: false });
+    hashers.update(data);
+    let manifest = dir.join(format!("{}.b2", name));
+    std::fs::write(&manifest, manifest::format_line(hashers.digests().blake2b.as_deref().unwrap(), &target)).unwrap();
+    manifest
+  }
+
+  #[tokio::test]
+  async fn test_check_ok() {
+    let dir = tempfile::tempdir().unwrap();
+    let manifest = manifest_for(dir.path(), "image", &[7u8; 3000]);
+    run(&manifest, ManifestFormat::B2sum, None, 512).await.unwrap();
+  }
+
+  #[tokio::test]
+  async fn test_check_detects_modification() {
+    let dir = tempfile::tempdir().unwrap();
+    let manifest = manifest_for(dir.path(), "image", &[7u8; 3000]);
+    std::fs::write(dir.path().join("image"), [8u8; 3000]).unwrap();
+    let result = run(&manifest, ManifestFormat::B2sum, None, 512).await;
+    assert!(matches!(result, Err(DdError::DigestMismatch(_))));
+  }
+
+  #[tokio::test]
+  async fn test_check_rejects_manifest_without_digests() {
+    let dir = tempfile::tempdir().unwrap();
+    let target = dir.path().join("image");
+    std::fs::write(&target, b"anything at all").unwrap();
+    let manifest = dir.path().join("image.json");
+    let record = manifest::ManifestRecord { job_id: uuid::Uuid::now_v7(), path: target, inode: 1, size: 15, digests: Default::default() };
+    std::fs::write(&manifest, serde_json::to_string(&record).unwrap()).unwrap();
+    // parsing refuses it, nothing would be hashed and anything would match
+    assert!(matches!(run(&manifest, ManifestFormat::Json, None, 512).await, Err(DdError::IoError(IoError::ManifestReadError(_)))));
+  }
+
+  #[tokio::test]
+  async fn test_check_missing_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let manifest = manifest_for(dir.path(), "image", b"abc");
+    std::fs::remove_file(dir.path().join("image")).unwrap();
+    let result = run(&manifest, ManifestFormat::B2sum, None, 512).await;
+    assert!(matches!(result, Err(DdError::DigestMismatch(_))));
+  }
+}
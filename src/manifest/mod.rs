@@ -0,0 +1,206 @@
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use crate::io::digest::Digests;
+use crate::io::error::IoError;
+
+pub mod check;
+
+/// Layout of a hash manifest file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Deserialize, serde::Serialize)]
+pub enum ManifestFormat {
+  /// `<blake2b hex>  <path>`, accepted by `b2sum -c`
+  B2sum,
+  /// `<sha3-512 hex>  <path>`, accepted by `sha3sum -c`
+  Sha3sum,
+  /// JSON record with all computed digests, inode, size and job id
+  Json,
+}
+
+/// JSON variant of the manifest.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct ManifestRecord {
+  pub job_id: uuid::Uuid,
+  pub path: PathBuf,
+  pub inode: u64,
+  pub size: u64,
+  pub digests: Digests,
+}
+
+/// Formats a single `<hex>  <path>` line the way coreutils does, escaping
+/// backslashes and newlines in the file name and marking the line with a leading `\`.
+pub fn format_line(digest: &str, path: &Path) -> String {
+  let name = path.to_string_lossy();
+  if name.contains('\\') || name.contains('\n') {
+    let escaped = name.replace('\\', "\\\\").replace('\n', "\\n");
+    format!("\\{}  {}\n", digest, escaped)
+  } else {
+    format!("{}  {}\n", digest, name)
+  }
+}
+
+/// Renders the manifest for `target` (the file the digests describe) in the requested format.
+pub fn render(format: ManifestFormat, target: &Path, digests: &Digests, job_id: uuid::Uuid) -> Result<String, IoError> {
+  let text = |digest: &Option<String>| match digest {
+    Some(digest) => Ok(format_line(digest, target)),
+    None => Err(IoError::ManifestWriteError(format!("{:?} manifest requires its digest to be computed", format))),
+  };
+  match format {
+    ManifestFormat::B2sum => text(&digests.blake2b),
+    ManifestFormat::Sha3sum => text(&digests.sha3_512),
+    ManifestFormat::Json => {
+      if digests.is_empty() {
+        return Err(IoError::ManifestWriteError("Json manifest requires a digest to be computed".to_string()));
+      }
+      let metadata = std::fs::metadata(target).map_err(|e| IoError::FileMetadataAcquireError(e.to_string()))?;
+      let record = ManifestRecord {
+        job_id,
+        path: target.to_path_buf(),
+        inode: metadata.ino(),
+        size: metadata.len(),
+        digests: digests.clone(),
+      };
+      let mut out = serde_json::to_string_pretty(&record).map_err(|e| IoError::ManifestWriteError(e.to_string()))?;
+      out.push('\n');
+      Ok(out)
+    }
+  }
+}
+
+/// Reverses the escaping done by `format_line`.
+fn unescape(name: &str) -> String {
+  let mut out = String::with_capacity(name.len());
+  let mut chars = name.chars();
+  while let Some(c) = chars.next() {
+    if c != '\\' {
+      out.push(c);
+      continue;
+    }
+    match chars.next() {
+      Some('n') => out.push('\n'),
+      Some(other) => out.push(other),
+      None => out.push('\\'),
+    }
+  }
+  out
+}
+
+/// Parses a manifest written in `format` into (path, expected digests) entries.
+pub fn parse(content: &str, format: ManifestFormat) -> Result<Vec<(PathBuf, Digests)>, IoError> {
+  if format == ManifestFormat::Json {
+    let record: ManifestRecord = serde_json::from_str(content).map_err(|e| IoError::ManifestReadError(e.to_string()))?;
+    if record.digests.is_empty() {
+      return Err(IoError::ManifestReadError(format!("{}: the record has no digests", record.path.display())));
+    }
+    return Ok(vec![(record.path, record.digests)]);
+  }
+
+  let mut entries = Vec::new();
+  for (number, line) in content.lines().enumerate() {
+    if line.trim().is_empty() || line.starts_with('#') {
+      continue;
+    }
+    let (escaped, line) = match line.strip_prefix('\\') {
+      Some(rest) => (true, rest),
+      None => (false, line),
+    };
+    // text mode uses two spaces, binary mode a space and an asterisk
+    let (digest, name) = line.split_once("  ")
+      .or_else(|| line.split_once(" *"))
+      .ok_or_else(|| IoError::ManifestReadError(format!("line {}: improperly formatted", number + 1)))?;
+    if digest.len() != 128 || !digest.bytes().all(|b| b.is_ascii_hexdigit()) {
+      return Err(IoError::ManifestReadError(format!("line {}: expected a 512-bit hex digest", number + 1)));
+    }
+    let name = if escaped { unescape(name) } else { name.to_string() };
+    let digest = Some(digest.to_ascii_lowercase());
+    let digests = match format {
+      ManifestFormat::B2sum => Digests { blake2b: digest, ..Digests::default() },
+      _ => Digests { sha3_512: digest, ..Digests::default() },
+    };
+    entries.push((PathBuf::from(name), digests));
+  }
+  Ok(entries)
+}
+
+#[tracing::instrument(skip(digests), level="debug", ret, err)]
+pub async fn write(manifest: &Path, format: ManifestFormat, target: &Path, digests: &Digests, job_id: uuid::Uuid) -> Result<(), IoError> {
+  let content = render(format, target, digests, job_id)?;
+  tokio::fs::write(manifest, content).await.map_err(|e| IoError::ManifestWriteError(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn digests() -> Digests {
+    Digests {
+      blake2b: Some("b2".repeat(64)),
+      sha3_512: Some("53".repeat(64)),
+      crc32: Some("352441c2".to_string()),
+    }
+  }
+
+  #[test]
+  fn test_format_line_plain() {
+    assert_eq!(format_line("abcd", Path::new("disk.img")), "abcd  disk.img\n");
+  }
+
+  #[test]
+  fn test_format_line_escaped() {
+    assert_eq!(format_line("abcd", Path::new("a\\b\nc")), "\\abcd  a\\\\b\\nc\n");
+  }
+
+  #[test]
+  fn test_render_text_formats() {
+    let id = uuid::Uuid::now_v7();
+    let b2 = render(ManifestFormat::B2sum, Path::new("x.img"), &digests(), id).unwrap();
+    assert_eq!(b2, format!("{}  x.img\n", "b2".repeat(64)));
+    let sha3 = render(ManifestFormat::Sha3sum, Path::new("x.img"), &digests(), id).unwrap();
+    assert_eq!(sha3, format!("{}  x.img\n", "53".repeat(64)));
+  }
+
+  #[test]
+  fn test_render_missing_digest() {
+    let digests = Digests { blake2b: None, ..digests() };
+    let result = render(ManifestFormat::B2sum, Path::new("x.img"), &digests, uuid::Uuid::now_v7());
+    assert!(matches!(result, Err(IoError::ManifestWriteError(_))));
+    let dir = tempfile::tempdir().unwrap();
+    let result = render(ManifestFormat::Json, dir.path(), &Digests::default(), uuid::Uuid::now_v7());
+    assert!(matches!(result, Err(IoError::ManifestWriteError(_))));
+  }
+
+  #[test]
+  fn test_parse_roundtrip() {
+    let id = uuid::Uuid::now_v7();
+    for name in ["x.img", "with space", "a\\b\nc"] {
+      let text = render(ManifestFormat::B2sum, Path::new(name), &digests(), id).unwrap();
+      let entries = parse(&text, ManifestFormat::B2sum).unwrap();
+      assert_eq!(entries, vec![(PathBuf::from(name), Digests { blake2b: digests().blake2b, ..Digests::default() })]);
+    }
+  }
+
+  #[test]
+  fn test_parse_rejects_garbage() {
+    assert!(parse("not a manifest\n", ManifestFormat::Sha3sum).is_err());
+    assert!(parse("abcd  short.img\n", ManifestFormat::Sha3sum).is_err());
+    assert!(parse("# comment\n\n", ManifestFormat::Sha3sum).unwrap().is_empty());
+  }
+
+  #[test]
+  fn test_render_json() {
+    let dir = tempfile::tempdir().unwrap();
+    let target = dir.path().join("image");
+    std::fs::write(&target, b"abc").unwrap();
+    let id = uuid::Uuid::now_v7();
+    let json = render(ManifestFormat::Json, &target, &digests(), id).unwrap();
+    let record: ManifestRecord = serde_json::from_str(&json).unwrap();
+    assert_eq!(record.job_id, id);
+    assert_eq!(record.size, 3);
+    assert_eq!(record.path, target);
+    assert_ne!(record.inode, 0);
+    assert_eq!(record.digests, digests());
+    let empty = ManifestRecord { digests: Digests::default(), ..record };
+    let json = serde_json::to_string(&empty).unwrap();
+    assert!(matches!(parse(&json, ManifestFormat::Json), Err(IoError::ManifestReadError(_))));
+  }
+}